@@ -0,0 +1,196 @@
+//! The containers and workloads every benchmark runs through.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::queue::SegQueue;
+use lockfreequeue::{EliminationStack, Stack};
+
+/// Enough of a container to push and pop from any number of threads.
+pub trait Container: Send + Sync + 'static {
+    const NAME: &'static str;
+
+    fn new() -> Self;
+
+    fn push(&self, t: u64);
+
+    fn pop(&self) -> Option<u64>;
+}
+
+impl Container for Stack<u64> {
+    const NAME: &'static str = "Stack";
+
+    fn new() -> Self {
+        Stack::new()
+    }
+
+    fn push(&self, t: u64) {
+        Stack::push(self, t)
+    }
+
+    fn pop(&self) -> Option<u64> {
+        Stack::pop(self)
+    }
+}
+
+impl Container for EliminationStack<u64> {
+    const NAME: &'static str = "EliminationStack";
+
+    fn new() -> Self {
+        EliminationStack::new()
+    }
+
+    fn push(&self, t: u64) {
+        EliminationStack::push(self, t)
+    }
+
+    fn pop(&self) -> Option<u64> {
+        EliminationStack::pop(self)
+    }
+}
+
+impl Container for SegQueue<u64> {
+    const NAME: &'static str = "SegQueue";
+
+    fn new() -> Self {
+        SegQueue::new()
+    }
+
+    fn push(&self, t: u64) {
+        SegQueue::push(self, t)
+    }
+
+    fn pop(&self) -> Option<u64> {
+        SegQueue::pop(self)
+    }
+}
+
+impl Container for Mutex<Vec<u64>> {
+    const NAME: &'static str = "Mutex<Vec>";
+
+    fn new() -> Self {
+        Mutex::new(Vec::new())
+    }
+
+    fn push(&self, t: u64) {
+        self.lock().unwrap().push(t)
+    }
+
+    fn pop(&self) -> Option<u64> {
+        self.lock().unwrap().pop()
+    }
+}
+
+/// A `std::sync::mpsc` channel, whose single receiver the popping threads take turns on.
+pub struct StdChannel {
+    sender: Sender<u64>,
+    receiver: Mutex<Receiver<u64>>,
+}
+
+impl Container for StdChannel {
+    const NAME: &'static str = "std::sync::mpsc";
+
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        StdChannel {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    fn push(&self, t: u64) {
+        self.sender.send(t).unwrap()
+    }
+
+    fn pop(&self) -> Option<u64> {
+        self.receiver.lock().unwrap().try_recv().ok()
+    }
+}
+
+/// How a thread's operations split between pushes and pops.
+#[derive(Clone, Copy, Debug)]
+pub enum Mix {
+    /// Three pushes to every pop.
+    ProducerHeavy,
+    Balanced,
+    /// Three pops to every push, on a container prefilled so they rarely come up empty.
+    ConsumerHeavy,
+}
+
+impl Mix {
+    pub const ALL: [Mix; 3] = [Mix::ProducerHeavy, Mix::Balanced, Mix::ConsumerHeavy];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mix::ProducerHeavy => "producer-heavy",
+            Mix::Balanced => "balanced",
+            Mix::ConsumerHeavy => "consumer-heavy",
+        }
+    }
+
+    /// Whether a thread's `i`th operation is a push.
+    pub fn is_push(self, i: usize) -> bool {
+        let pushes = match self {
+            Mix::ProducerHeavy => 3,
+            Mix::Balanced => 2,
+            Mix::ConsumerHeavy => 1,
+        };
+        i % 4 < pushes
+    }
+
+    /// How many elements to push before `threads` threads run `ops` operations each.
+    pub fn prefill(self, threads: usize, ops: usize) -> usize {
+        match self {
+            Mix::ConsumerHeavy => threads * ops / 2,
+            Mix::ProducerHeavy | Mix::Balanced => 0,
+        }
+    }
+}
+
+/// 1, 2, 4, 8 and on up to the number of cores.
+pub fn thread_counts() -> Vec<usize> {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut counts = vec![1];
+    while *counts.last().unwrap() < cores.max(8) {
+        counts.push(counts.last().unwrap() * 2);
+    }
+    counts
+}
+
+/// Runs `body` on `threads` threads at once, on a container prefilled for `ops` operations a thread.
+///
+/// `body` gets the container and the thread's index. Returns how long the
+/// threads took from being released together, and what each of them returned.
+pub fn run<C: Container, R: Send + 'static>(
+    mix: Mix,
+    threads: usize,
+    ops: usize,
+    body: impl Fn(&C, usize) -> R + Send + Sync + 'static,
+) -> (Duration, Vec<R>) {
+    let container = Arc::new(C::new());
+    for i in 0..mix.prefill(threads, ops) {
+        container.push(i as u64);
+    }
+
+    // everyone waits here, so thread start-up stays out of the measurement
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let body = Arc::new(body);
+    let handles: Vec<_> = (0..threads)
+        .map(|thread| {
+            let (container, barrier, body) = (container.clone(), barrier.clone(), body.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                body(&container, thread)
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    let results = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+    (start.elapsed(), results)
+}
@@ -0,0 +1,65 @@
+//! Per-operation latency percentiles of [`Stack`] against other containers,
+//! for every workload mix and thread count.
+//!
+//! Criterion only reports the mean of whole iterations, so this times every
+//! single push and pop itself and prints a table.
+
+mod common;
+
+use std::hint::black_box;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crossbeam::queue::SegQueue;
+use lockfreequeue::{EliminationStack, Stack};
+
+use crate::common::{Container, Mix, StdChannel};
+
+// operations per thread, each timed on its own
+const OPS: usize = 100_000;
+
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+fn main() {
+    print!("{:<16} {:<16} {:>7}", "mix", "container", "threads");
+    for p in PERCENTILES {
+        print!(" {:>9}", format!("p{}", p));
+    }
+    println!(" {:>9}", "max");
+
+    for mix in Mix::ALL {
+        for threads in common::thread_counts() {
+            report::<Stack<u64>>(mix, threads);
+            report::<EliminationStack<u64>>(mix, threads);
+            report::<SegQueue<u64>>(mix, threads);
+            report::<Mutex<Vec<u64>>>(mix, threads);
+            report::<StdChannel>(mix, threads);
+        }
+    }
+}
+
+fn report<C: Container>(mix: Mix, threads: usize) {
+    let (_, latencies) = common::run::<C, _>(mix, threads, OPS, move |c, _| {
+        let mut latencies = Vec::with_capacity(OPS);
+        for i in 0..OPS {
+            let start = Instant::now();
+            if mix.is_push(i) {
+                c.push(i as u64);
+            } else {
+                black_box(c.pop());
+            }
+            latencies.push(start.elapsed().as_nanos() as u64);
+        }
+        latencies
+    });
+
+    let mut latencies: Vec<_> = latencies.into_iter().flatten().collect();
+    latencies.sort_unstable();
+
+    print!("{:<16} {:<16} {:>7}", mix.name(), C::NAME, threads);
+    for p in PERCENTILES {
+        let rank = ((p / 100.0) * (latencies.len() - 1) as f64).round() as usize;
+        print!(" {:>7}ns", latencies[rank]);
+    }
+    println!(" {:>7}ns", latencies.last().unwrap());
+}
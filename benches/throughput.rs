@@ -0,0 +1,67 @@
+//! Push/pop throughput of [`Stack`] against other containers, for every
+//! workload mix and thread count.
+
+mod common;
+
+use std::hint::black_box;
+use std::sync::Mutex;
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use crossbeam::queue::SegQueue;
+use lockfreequeue::{EliminationStack, Stack};
+
+use crate::common::{Container, Mix, StdChannel};
+
+// operations per thread and iteration
+const OPS: usize = 10_000;
+
+fn throughput(c: &mut Criterion) {
+    for mix in Mix::ALL {
+        let mut group = c.benchmark_group(mix.name());
+        // every sample spawns its threads anew, so fewer of them keeps a run bearable
+        group.sample_size(20);
+
+        for threads in common::thread_counts() {
+            group.throughput(Throughput::Elements((threads * OPS) as u64));
+
+            bench::<Stack<u64>>(&mut group, mix, threads);
+            bench::<EliminationStack<u64>>(&mut group, mix, threads);
+            bench::<SegQueue<u64>>(&mut group, mix, threads);
+            bench::<Mutex<Vec<u64>>>(&mut group, mix, threads);
+            bench::<StdChannel>(&mut group, mix, threads);
+        }
+
+        group.finish();
+    }
+}
+
+fn bench<C: Container>(group: &mut BenchmarkGroup<'_, WallTime>, mix: Mix, threads: usize) {
+    group.bench_with_input(
+        BenchmarkId::new(C::NAME, threads),
+        &threads,
+        |b, &threads| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        let (elapsed, _) = common::run::<C, _>(mix, threads, OPS, move |c, _| {
+                            for i in 0..OPS {
+                                if mix.is_push(i) {
+                                    c.push(i as u64);
+                                } else {
+                                    black_box(c.pop());
+                                }
+                            }
+                        });
+                        elapsed
+                    })
+                    .sum()
+            })
+        },
+    );
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
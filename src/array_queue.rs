@@ -0,0 +1,528 @@
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+
+use crate::backoff::{Backoff, Exponential};
+use crate::cache_padded::CachePadded;
+use crate::sync::atomic::{fence, AtomicUsize};
+use crate::sync::{spin_loop, UnsafeCell};
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::waiter::Waiters;
+
+/// A bounded multi-producer multi-consumer FIFO queue.
+///
+/// All slots are allocated up front, so pushing and popping never allocate.
+/// Every slot carries a sequence number that tells a producer or consumer
+/// arriving at position `pos` whether the slot is ready for it (Vyukov's
+/// bounded queue).
+///
+/// A position keeps the slot index in its low bits and the lap above them, so
+/// positions wrapping around at `usize::MAX` land on the slot they would have
+/// anyway, whatever the capacity.
+///
+/// A pop that finds its slot claimed by a push that is still writing waits
+/// for it rather than report the queue empty, and likewise for a push that
+/// finds a pop still reading.
+///
+/// That makes the queue linearizable, but not lock-free: a push preempted
+/// between claiming its slot and writing it holds up every pop that reaches
+/// the slot until it resumes, and likewise a preempted pop holds up pushes a
+/// lap later. crossbeam's `ArrayQueue` makes the same trade.
+pub struct ArrayQueue<T, A: Allocator = Global, B = Exponential> {
+    buffer: Box<[Slot<T>], A>,
+    // what a position moves by per lap, the power of two above the last slot index
+    one_lap: usize,
+    // position of the next pop
+    head: CachePadded<AtomicUsize>,
+    // position of the next push
+    tail: CachePadded<AtomicUsize>,
+    waiters: Waiters,
+    backoff: B,
+}
+
+unsafe impl<T: Send, A: Allocator + Send, B: Send> Send for ArrayQueue<T, A, B> {}
+
+unsafe impl<T: Send, A: Allocator + Sync, B: Sync> Sync for ArrayQueue<T, A, B> {}
+
+struct Slot<T> {
+    // `free(pos)` when the slot is free for the push at `pos`,
+    // `full(pos)` when it holds the data pushed at `pos`
+    sequence: AtomicUsize,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> ArrayQueue<T> {
+    /// Creates a queue that holds at most `capacity` elements.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> ArrayQueue<T> {
+        ArrayQueue::new_in(capacity, Global)
+    }
+}
+
+impl<T, A: Allocator> ArrayQueue<T, A> {
+    /// Like [`ArrayQueue::new`], but allocates the slots from `alloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new_in(capacity: usize, alloc: A) -> ArrayQueue<T, A> {
+        assert!(capacity > 0, "capacity must be non-zero");
+
+        let mut buffer = Vec::with_capacity_in(capacity, alloc);
+        buffer.extend((0..capacity).map(|i| Slot {
+            sequence: AtomicUsize::new(free(i)),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }));
+        let buffer = buffer.into_boxed_slice();
+
+        ArrayQueue {
+            buffer,
+            one_lap: (capacity + 1).next_power_of_two(),
+            head: CachePadded::new(AtomicUsize::new(0)),
+            tail: CachePadded::new(AtomicUsize::new(0)),
+            waiters: Waiters::new(),
+            backoff: Exponential::default(),
+        }
+    }
+}
+
+impl<T, A: Allocator, B: Backoff> ArrayQueue<T, A, B> {
+    /// Makes the queue pause through `backoff` whenever a CAS on `head` or `tail` loses a race.
+    pub fn with_backoff<B2: Backoff>(self, backoff: B2) -> ArrayQueue<T, A, B2> {
+        let this = ManuallyDrop::new(self);
+
+        // move everything over, `this` is never dropped so the elements stay ours
+        unsafe {
+            drop(ptr::read(&this.backoff));
+            ArrayQueue {
+                buffer: ptr::read(&this.buffer),
+                one_lap: this.one_lap,
+                head: ptr::read(&this.head),
+                tail: ptr::read(&this.tail),
+                waiters: ptr::read(&this.waiters),
+                backoff,
+            }
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Roughly how many elements the queue holds.
+    ///
+    /// Counts every position claimed by a push and not yet by a pop, so
+    /// operations racing with this may or may not show up.
+    pub fn len(&self) -> usize {
+        loop {
+            let tail = self.tail.load(SeqCst);
+            let head = self.head.load(SeqCst);
+
+            // only trust `head` if `tail` didn't move while we read it
+            if self.tail.load(SeqCst) != tail {
+                continue;
+            }
+
+            let head_index = head & (self.one_lap - 1);
+            let tail_index = tail & (self.one_lap - 1);
+            return if head_index < tail_index {
+                tail_index - head_index
+            } else if head_index > tail_index {
+                self.capacity() - head_index + tail_index
+            } else if head == tail {
+                0
+            } else {
+                self.capacity()
+            };
+        }
+    }
+
+    /// Whether the queue is empty, which may no longer hold by the time this returns.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes `t` onto the back of the queue, handing it back if the queue is full.
+    pub fn try_push(&self, t: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Relaxed);
+        let mut attempt = 0;
+
+        loop {
+            let slot = self.slot(pos);
+            let sequence = slot.sequence.load(Acquire);
+            let diff = sequence.wrapping_sub(free(pos)) as isize;
+
+            if diff == 0 {
+                // the slot is free, try to claim `pos` for ourselves
+                match self
+                    .tail
+                    .compare_exchange_weak(pos, self.next(pos), Relaxed, Relaxed)
+                {
+                    Ok(_) => {
+                        slot.data
+                            .with_mut(|data| unsafe { (*data).as_mut_ptr().write(t) });
+                        slot.sequence.store(full(pos), Release);
+                        self.waiters.notify();
+                        return Ok(());
+                    }
+                    Err(current) => {
+                        self.backoff.backoff(attempt);
+                        attempt += 1;
+                        pos = current;
+                    }
+                }
+            } else if diff < 0 {
+                // the slot still holds the data from one lap ago, which is only
+                // full if that element has not been claimed by a pop yet
+                fence(SeqCst);
+                if self.head.load(Relaxed).wrapping_add(self.one_lap) == pos {
+                    return Err(t);
+                }
+
+                // a pop claimed it and is still reading, reporting full would
+                // ignore any pop that finished behind it
+                spin_loop();
+                pos = self.tail.load(Relaxed);
+            } else {
+                // another producer claimed `pos` already
+                self.backoff.backoff(attempt);
+                attempt += 1;
+                pos = self.tail.load(Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Relaxed);
+        let mut attempt = 0;
+
+        loop {
+            let slot = self.slot(pos);
+            let sequence = slot.sequence.load(Acquire);
+            let diff = sequence.wrapping_sub(full(pos)) as isize;
+
+            if diff == 0 {
+                // the slot holds data, try to claim `pos` for ourselves
+                match self
+                    .head
+                    .compare_exchange_weak(pos, self.next(pos), Relaxed, Relaxed)
+                {
+                    Ok(_) => {
+                        let data = slot.data.with(|data| unsafe { (*data).as_ptr().read() });
+                        // hand the slot to the push one lap ahead
+                        slot.sequence
+                            .store(free(pos.wrapping_add(self.one_lap)), Release);
+                        return Some(data);
+                    }
+                    Err(current) => {
+                        self.backoff.backoff(attempt);
+                        attempt += 1;
+                        pos = current;
+                    }
+                }
+            } else if diff < 0 {
+                // nothing has been pushed at `pos` yet, so empty unless a push claimed it
+                fence(SeqCst);
+                if self.tail.load(Relaxed) == pos {
+                    return None;
+                }
+
+                // a push claimed it and is still writing, reporting empty would
+                // ignore any push that finished behind it
+                spin_loop();
+                pos = self.head.load(Relaxed);
+            } else {
+                // another consumer claimed `pos` already
+                self.backoff.backoff(attempt);
+                attempt += 1;
+                pos = self.head.load(Relaxed);
+            }
+        }
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the queue is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&self) -> T {
+        self.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`ArrayQueue::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`ArrayQueue::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&self, deadline: Instant) -> Option<T> {
+        self.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the queue is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&self) -> impl Future<Output = T> + '_ {
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| self.waiters.poll_pop(cx, &mut registration, || self.pop()))
+    }
+}
+
+// sequence numbers count in half steps, so a slot holding the data pushed at
+// `pos` never looks free to the push at any other position
+fn free(pos: usize) -> usize {
+    pos.wrapping_mul(2)
+}
+
+fn full(pos: usize) -> usize {
+    pos.wrapping_mul(2).wrapping_add(1)
+}
+
+impl<T, A: Allocator, B> ArrayQueue<T, A, B> {
+    fn slot(&self, pos: usize) -> &Slot<T> {
+        &self.buffer[pos & (self.one_lap - 1)]
+    }
+
+    // the position after `pos`, which skips to the start of the next lap after the last slot
+    fn next(&self, pos: usize) -> usize {
+        if (pos & (self.one_lap - 1)) + 1 < self.buffer.len() {
+            pos.wrapping_add(1)
+        } else {
+            (pos & !(self.one_lap - 1)).wrapping_add(self.one_lap)
+        }
+    }
+}
+
+impl<T, A: Allocator, B> Drop for ArrayQueue<T, A, B> {
+    fn drop(&mut self) {
+        let head = self.head.load(Relaxed);
+        let tail = self.tail.load(Relaxed);
+
+        // with `&mut self` every claimed position has been fully written
+        let mut pos = head;
+        while pos != tail {
+            self.slot(pos)
+                .data
+                .with_mut(|data| unsafe { (*data).as_mut_ptr().drop_in_place() });
+            pos = self.next(pos);
+        }
+    }
+}
+
+#[test]
+fn push_items() {
+    let queue = ArrayQueue::new(3);
+
+    queue.try_push(10).unwrap();
+    queue.try_push(5).unwrap();
+    queue.try_push(1).unwrap();
+    assert_eq!(queue.try_push(7), Err(7));
+
+    assert_eq!(queue.pop().unwrap(), 10);
+    assert_eq!(queue.pop().unwrap(), 5);
+    assert_eq!(queue.pop().unwrap(), 1);
+    assert!(queue.pop().is_none());
+}
+
+#[test]
+fn wraps_around() {
+    let queue = ArrayQueue::new(3);
+
+    for i in 0..100 {
+        queue.try_push(i).unwrap();
+        queue.try_push(i + 1).unwrap();
+        assert_eq!(queue.pop().unwrap(), i);
+        assert_eq!(queue.pop().unwrap(), i + 1);
+    }
+    assert!(queue.pop().is_none());
+}
+
+#[test]
+fn capacity_one() {
+    let queue = ArrayQueue::new(1);
+
+    for i in 0..3 {
+        queue.try_push(i).unwrap();
+        assert_eq!(queue.try_push(i + 1), Err(i + 1));
+        assert_eq!(queue.pop(), Some(i));
+        assert!(queue.pop().is_none());
+    }
+}
+
+// a queue one slot into the last lap before positions wrap, with the slots set up as if it got there
+#[cfg(test)]
+fn near_wrap<T>(capacity: usize) -> ArrayQueue<T> {
+    let queue = ArrayQueue::new(capacity);
+
+    let start = 0usize.wrapping_sub(queue.one_lap) + 1;
+    queue.head.store(start, Relaxed);
+    queue.tail.store(start, Relaxed);
+    let mut pos = start;
+    for _ in 0..queue.capacity() {
+        queue.slot(pos).sequence.store(free(pos), Relaxed);
+        pos = queue.next(pos);
+    }
+
+    queue
+}
+
+#[test]
+fn wraps_around_usize() {
+    let queue = near_wrap(3);
+
+    for i in 0..10 {
+        queue.try_push(vec![i]).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop(), Some(vec![i]));
+    }
+    for round in 0..4 {
+        for i in 0..3 {
+            queue.try_push(vec![round, i]).unwrap();
+        }
+        assert_eq!(queue.try_push(vec![]), Err(vec![]));
+        assert_eq!(queue.len(), 3);
+        for i in 0..3 {
+            assert_eq!(queue.pop(), Some(vec![round, i]));
+        }
+        assert!(queue.pop().is_none());
+    }
+
+    queue.try_push(vec![1]).unwrap();
+    drop(queue);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn pop_timeout() {
+    let queue = ArrayQueue::new(1);
+    assert!(queue.pop_timeout(Duration::from_millis(10)).is_none());
+
+    queue.try_push(1).unwrap();
+    assert_eq!(queue.pop_deadline(Instant::now()), Some(1));
+}
+
+#[test]
+fn drop_remaining() {
+    use std::sync::Arc;
+
+    let item = Arc::new(());
+
+    let queue = ArrayQueue::new(8);
+    for _ in 0..5 {
+        queue.try_push(item.clone()).unwrap();
+    }
+    drop(queue.pop());
+    drop(queue);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[test]
+fn len_across_lap_wrap() {
+    let queue = near_wrap(3);
+
+    // each round starts one slot further on, so the head passes the tail's index and the lap wraps
+    for round in 0..8 {
+        for i in 0..3 {
+            queue.try_push(round).unwrap();
+            assert_eq!(queue.len(), i + 1);
+        }
+        assert_eq!(queue.try_push(round), Err(round));
+        assert_eq!(queue.len(), 3);
+
+        for i in 0..3 {
+            queue.pop().unwrap();
+            assert_eq!(queue.len(), 2 - i);
+        }
+        assert!(queue.is_empty());
+
+        queue.try_push(round).unwrap();
+        queue.pop().unwrap();
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn backs_off_on_lost_cas() {
+    use crate::backoff::{self, Counting};
+
+    let backoff = Counting::default();
+    let queue = ArrayQueue::new(4).with_backoff(backoff.clone());
+
+    backoff::collide(&backoff, || {
+        let _ = queue.try_push(1);
+        queue.pop();
+    });
+}
+
+#[test]
+fn custom_allocator() {
+    use crate::raw::CountingAlloc;
+
+    let alloc = CountingAlloc::default();
+    let queue = ArrayQueue::new_in(4, alloc.clone());
+    queue.try_push(1).unwrap();
+    assert_eq!(queue.pop(), Some(1));
+
+    assert_eq!(alloc.live.load(Relaxed), 1);
+    drop(queue);
+    assert_eq!(alloc.live.load(Relaxed), 0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::sync::Arc;
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const THREADS: usize = 4;
+
+    let queue = Arc::new(ArrayQueue::new(16));
+
+    let producers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = queue.clone();
+            thread::spawn(move || {
+                for i in 0..RUNS {
+                    let mut item = i;
+                    while let Err(back) = our_copy.try_push(item) {
+                        item = back;
+                        thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let consumers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = queue.clone();
+            thread::spawn(move || {
+                let mut sum = 0;
+                for _ in 0..RUNS {
+                    sum += our_copy.pop_blocking();
+                }
+                sum
+            })
+        })
+        .collect();
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    let sum: usize = consumers.into_iter().map(|c| c.join().unwrap()).sum();
+
+    assert_eq!(sum, THREADS * RUNS * (RUNS - 1) / 2);
+    assert!(queue.pop().is_none());
+}
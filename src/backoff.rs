@@ -0,0 +1,163 @@
+//! Strategies for pausing between the attempts of a CAS retry loop.
+//!
+//! Every container that retries a CAS takes a [`Backoff`] as a type parameter,
+//! [`Exponential`] unless picked otherwise through its `with_backoff`. Backing
+//! off after losing a race gives the winner's cache line a moment to settle
+//! instead of hammering it again right away.
+
+use crate::sync::spin_loop;
+#[cfg(feature = "std")]
+use crate::sync::yield_now;
+
+/// The exponent [`Exponential`] and [`Adaptive`] stop growing at unless told otherwise.
+const DEFAULT_LIMIT: u32 = 6;
+
+/// How a retry loop waits after losing a race.
+pub trait Backoff {
+    /// Pauses after `attempt` failed attempts in a row, counting from zero.
+    fn backoff(&self, attempt: u32);
+}
+
+/// A single spin-loop hint per failed attempt.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spin;
+
+/// `2^attempt` spin-loop hints, up to `2^limit`.
+#[derive(Clone, Copy, Debug)]
+pub struct Exponential {
+    limit: u32,
+}
+
+/// Hands the rest of the time slice to another thread.
+///
+/// Best when there are more threads than cores, where the thread that won
+/// the race may not even be running.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Yield;
+
+/// Spins like [`Exponential`] for the first `limit` attempts, then yields the
+/// thread on every one after.
+///
+/// Without `std` there is no thread to yield, and it keeps spinning `2^limit` times.
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    limit: u32,
+}
+
+impl Backoff for Spin {
+    fn backoff(&self, _attempt: u32) {
+        spin_loop();
+    }
+}
+
+impl Exponential {
+    /// Spins at most `2^limit` times per attempt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 32 or more.
+    pub fn new(limit: u32) -> Exponential {
+        assert!(limit < u32::BITS, "limit must be below 32");
+        Exponential { limit }
+    }
+}
+
+impl Default for Exponential {
+    fn default() -> Exponential {
+        Exponential::new(DEFAULT_LIMIT)
+    }
+}
+
+impl Backoff for Exponential {
+    fn backoff(&self, attempt: u32) {
+        spin(attempt.min(self.limit));
+    }
+}
+
+#[cfg(feature = "std")]
+impl Backoff for Yield {
+    fn backoff(&self, _attempt: u32) {
+        yield_now();
+    }
+}
+
+impl Adaptive {
+    /// Spins for the first `limit` attempts, and yields from then on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 32 or more.
+    pub fn new(limit: u32) -> Adaptive {
+        assert!(limit < u32::BITS, "limit must be below 32");
+        Adaptive { limit }
+    }
+}
+
+impl Default for Adaptive {
+    fn default() -> Adaptive {
+        Adaptive::new(DEFAULT_LIMIT)
+    }
+}
+
+impl Backoff for Adaptive {
+    fn backoff(&self, attempt: u32) {
+        if attempt < self.limit {
+            spin(attempt);
+        } else {
+            #[cfg(feature = "std")]
+            yield_now();
+            #[cfg(not(feature = "std"))]
+            spin(self.limit);
+        }
+    }
+}
+
+fn spin(exponent: u32) {
+    for _ in 0..1u32 << exponent {
+        spin_loop();
+    }
+}
+
+/// Counts how often it is called, the count shared between clones.
+#[cfg(all(test, feature = "std"))]
+#[derive(Clone, Default)]
+pub(crate) struct Counting(std::sync::Arc<core::sync::atomic::AtomicUsize>);
+
+#[cfg(all(test, feature = "std"))]
+impl Counting {
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(core::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+impl Backoff for Counting {
+    fn backoff(&self, _attempt: u32) {
+        self.0.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Runs `op` on a few threads at once until one of them loses a race and backs off through `backoff`.
+///
+/// # Panics
+///
+/// Panics if none has after a minute.
+#[cfg(all(test, feature = "std"))]
+pub(crate) fn collide(backoff: &Counting, op: impl Fn() + Sync) {
+    use std::time::{Duration, Instant};
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                while backoff.count() == 0 {
+                    assert!(Instant::now() < deadline, "no CAS ever lost a race");
+                    for _ in 0..1_000 {
+                        op();
+                    }
+                }
+            });
+        }
+    });
+}
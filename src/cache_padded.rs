@@ -0,0 +1,52 @@
+use core::ops::Deref;
+
+/// Pads and aligns a value to a cache line of its own.
+///
+/// Hot atomics that different threads write, like a stack's `head` or a
+/// queue's `head` and `tail`, would otherwise share a line with whatever sits
+/// next to them, and every write would take that line away from the other
+/// threads reading their neighbour (false sharing).
+///
+/// x86-64 prefetches lines in pairs and recent aarch64 and powerpc64 cores
+/// have 128-byte lines, so those get 128 bytes, everything else 64.
+#[cfg_attr(
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    ),
+    repr(align(128))
+)]
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    )),
+    repr(align(64))
+)]
+pub(crate) struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub(crate) fn new(t: T) -> CachePadded<T> {
+        CachePadded(t)
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[test]
+fn own_line() {
+    let pair = [CachePadded::new(0u8), CachePadded::new(0u8)];
+
+    let first = &*pair[0] as *const u8 as usize;
+    let second = &*pair[1] as *const u8 as usize;
+    assert!(second - first >= 64);
+    assert_eq!(first % 64, 0);
+}
@@ -0,0 +1,547 @@
+//! An unbounded multi-producer multi-consumer channel built on [`Queue`].
+//!
+//! Unlike a bare queue, a channel knows how many [`Sender`]s and [`Receiver`]s
+//! are still around, so it can tell "empty for now" apart from "nobody will
+//! ever send again".
+
+use alloc::sync::Arc;
+use core::error::Error;
+use core::fmt;
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+#[cfg(feature = "async")]
+use core::pin::Pin;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+#[cfg(feature = "async")]
+use core::task::{Context, Poll};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use futures_core::Stream;
+#[cfg(feature = "async")]
+use futures_sink::Sink;
+
+use allocator_api2::alloc::{Allocator, Global};
+
+use crate::reclaim::{DefaultReclaimer, Reclaimer};
+use crate::sync::atomic::AtomicUsize;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::Queue;
+
+struct Chan<T, R, A: Allocator> {
+    queue: Queue<T, R, A>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+}
+
+/// The sending half of a [`channel`].
+pub struct Sender<T, R = DefaultReclaimer, A: Allocator = Global> {
+    chan: Arc<Chan<T, R, A>>,
+    // once closed we no longer count as a sender
+    closed: bool,
+}
+
+/// The receiving half of a [`channel`].
+pub struct Receiver<T, R = DefaultReclaimer, A: Allocator = Global> {
+    chan: Arc<Chan<T, R, A>>,
+    // where the stream waits, each clone has its own
+    #[cfg(feature = "async")]
+    registration: Registration,
+}
+
+/// Returned by [`Sender::send`] once every [`Receiver`] is gone, or the sender
+/// was closed through its `Sink` impl, hands back the value.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct SendError<T>(pub T);
+
+/// Returned by [`Receiver::try_recv`] when there is nothing to receive.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryRecvError {
+    /// Nothing has been sent yet, but a [`Sender`] is still around.
+    Empty,
+    /// Every [`Sender`] is gone and everything sent has been received.
+    Disconnected,
+}
+
+/// Returned by [`Receiver::recv`] once every [`Sender`] is gone and everything sent has been received.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RecvError;
+
+/// Returned by [`Receiver::recv_timeout`] and [`Receiver::recv_deadline`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecvTimeoutError {
+    /// Nothing arrived in time, but a [`Sender`] is still around.
+    Timeout,
+    /// Every [`Sender`] is gone and everything sent has been received.
+    Disconnected,
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Chan<T, R, A> {
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(t) = self.queue.pop() {
+            return Ok(t);
+        }
+
+        if self.senders.load(Acquire) == 0 {
+            // the last sender may have pushed right before leaving, look once more
+            return self.queue.pop().ok_or(TryRecvError::Disconnected);
+        }
+
+        Err(TryRecvError::Empty)
+    }
+
+    // `None` while a receiver should keep waiting
+    #[cfg(any(feature = "std", feature = "async"))]
+    fn ready(&self) -> Option<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(t) => Some(Ok(t)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+        }
+    }
+}
+
+/// Creates a channel, returning its first [`Sender`] and [`Receiver`].
+#[cfg(feature = "flize")]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    channel_with_reclaimer(DefaultReclaimer::default())
+}
+
+/// Like [`channel`], but the queue underneath allocates its nodes from `alloc`.
+///
+/// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+/// and how to use a short-lived arena anyway.
+#[cfg(feature = "flize")]
+pub fn channel_in<T, A: Allocator + Clone + 'static>(
+    alloc: A,
+) -> (
+    Sender<T, DefaultReclaimer, A>,
+    Receiver<T, DefaultReclaimer, A>,
+) {
+    channel_with_reclaimer_in(DefaultReclaimer::default(), alloc)
+}
+
+/// Like [`channel`], but the queue underneath reclaims its nodes through `reclaimer`.
+pub fn channel_with_reclaimer<T, R: Reclaimer>(reclaimer: R) -> (Sender<T, R>, Receiver<T, R>) {
+    channel_with_reclaimer_in(reclaimer, Global)
+}
+
+/// Like [`channel`], but the queue underneath allocates its nodes from `alloc`
+/// and reclaims them through `reclaimer`.
+///
+/// `alloc` has to be `'static`, see [allocators](crate#allocators).
+pub fn channel_with_reclaimer_in<T, R: Reclaimer, A: Allocator + Clone + 'static>(
+    reclaimer: R,
+    alloc: A,
+) -> (Sender<T, R, A>, Receiver<T, R, A>) {
+    let chan = Arc::new(Chan {
+        queue: Queue::with_reclaimer_in(reclaimer, alloc),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    });
+
+    let sender = Sender {
+        chan: chan.clone(),
+        closed: false,
+    };
+    let receiver = Receiver {
+        chan,
+        #[cfg(feature = "async")]
+        registration: Registration::new(),
+    };
+
+    (sender, receiver)
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Sender<T, R, A> {
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        if self.closed || self.chan.receivers.load(Acquire) == 0 {
+            return Err(SendError(t));
+        }
+
+        self.chan.queue.push(t);
+        Ok(())
+    }
+
+    /// Whether the channel is empty, which may no longer hold by the time this returns.
+    pub fn is_empty(&self) -> bool {
+        self.chan.queue.is_empty()
+    }
+
+    /// Roughly how many elements are waiting to be received, see [`Queue::len`].
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.chan.queue.len()
+    }
+}
+
+impl<T, R, A: Allocator> Sender<T, R, A> {
+    // gives up our share of the senders, disconnecting the channel if it was the last one
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+
+        // releases our pushes to whoever sees the count hit zero
+        if self.chan.senders.fetch_sub(1, AcqRel) == 1 {
+            // wake every parked receiver so they can all see the disconnect
+            self.chan.queue.waiters().notify_all();
+        }
+    }
+}
+
+impl<T, R, A: Allocator> Clone for Sender<T, R, A> {
+    /// Clones the sender, a clone of a closed one is closed too.
+    fn clone(&self) -> Sender<T, R, A> {
+        if !self.closed {
+            self.chan.senders.fetch_add(1, Relaxed);
+        }
+
+        Sender {
+            chan: self.chan.clone(),
+            closed: self.closed,
+        }
+    }
+}
+
+impl<T, R, A: Allocator> Drop for Sender<T, R, A> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Receiver<T, R, A> {
+    /// Whether the channel is empty, which may no longer hold by the time this returns.
+    pub fn is_empty(&self) -> bool {
+        self.chan.queue.is_empty()
+    }
+
+    /// Roughly how many elements are waiting to be received, see [`Queue::len`].
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.chan.queue.len()
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.chan.try_recv()
+    }
+
+    /// Receives an element, parking the thread until one is sent if the channel is empty.
+    #[cfg(feature = "std")]
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.chan
+            .queue
+            .waiters()
+            .wait_until(None, || self.chan.ready())
+            .unwrap()
+    }
+
+    /// Like [`Receiver::recv`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let ready = self
+            .chan
+            .queue
+            .waiters()
+            .wait_for(timeout, || self.chan.ready());
+        Self::timed_out(ready)
+    }
+
+    /// Like [`Receiver::recv`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        let ready = self
+            .chan
+            .queue
+            .waiters()
+            .wait_until(Some(deadline), || self.chan.ready());
+        Self::timed_out(ready)
+    }
+
+    /// Receives an element, waiting for one to be sent if the channel is empty.
+    #[cfg(feature = "async")]
+    pub fn recv_async(&self) -> impl Future<Output = Result<T, RecvError>> + '_ {
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| {
+            self.chan
+                .queue
+                .waiters()
+                .poll_pop(cx, &mut registration, || self.chan.ready())
+        })
+    }
+
+    #[cfg(feature = "std")]
+    fn timed_out(ready: Option<Result<T, RecvError>>) -> Result<T, RecvTimeoutError> {
+        match ready {
+            Some(Ok(t)) => Ok(t),
+            Some(Err(RecvError)) => Err(RecvTimeoutError::Disconnected),
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+}
+
+impl<T, R, A: Allocator> Clone for Receiver<T, R, A> {
+    fn clone(&self) -> Receiver<T, R, A> {
+        self.chan.receivers.fetch_add(1, Relaxed);
+
+        Receiver {
+            chan: self.chan.clone(),
+            #[cfg(feature = "async")]
+            registration: Registration::new(),
+        }
+    }
+}
+
+impl<T, R, A: Allocator> Drop for Receiver<T, R, A> {
+    fn drop(&mut self) {
+        self.chan.receivers.fetch_sub(1, AcqRel);
+    }
+}
+
+/// Yields received elements, and ends once every [`Sender`] is gone and everything sent has been received.
+#[cfg(feature = "async")]
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Stream for Receiver<T, R, A> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let Receiver { chan, registration } = self.get_mut();
+        chan.queue
+            .waiters()
+            .poll_pop(cx, registration, || chan.ready())
+            .map(Result::ok)
+    }
+}
+
+/// The channel is unbounded, so a [`Sender`] is always ready and never needs flushing.
+///
+/// Closing a sender disconnects it as dropping it would, and sends through it fail from then on.
+#[cfg(feature = "async")]
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Sink<T> for Sender<T, R, A> {
+    type Error = SendError<T>;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, t: T) -> Result<(), SendError<T>> {
+        self.send(t)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        self.get_mut().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("SendError { .. }")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("sending on a channel with no receivers")
+    }
+}
+
+impl<T> Error for SendError<T> {}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.pad("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.pad("receiving on an empty channel with no senders"),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("receiving on an empty channel with no senders")
+    }
+}
+
+impl Error for RecvError {}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.pad("timed out waiting on a channel"),
+            RecvTimeoutError::Disconnected => {
+                f.pad("receiving on an empty channel with no senders")
+            }
+        }
+    }
+}
+
+impl Error for RecvTimeoutError {}
+
+#[test]
+fn send_items() {
+    let (sender, receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+
+    sender.send(10).unwrap();
+    sender.send(5).unwrap();
+
+    assert_eq!(receiver.try_recv(), Ok(10));
+    assert_eq!(receiver.try_recv(), Ok(5));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn disconnect() {
+    let (sender, receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+
+    let second = sender.clone();
+    sender.send(1).unwrap();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+    // buffered items still come out after the last sender leaves
+    second.send(2).unwrap();
+    drop(second);
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+
+    let (sender, receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+    let second = receiver.clone();
+    drop(receiver);
+    sender.send(1).unwrap();
+    drop(second);
+    assert_eq!(sender.send(2), Err(SendError(2)));
+}
+
+#[test]
+fn len_ignores_failed_sends() {
+    let (sender, receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+
+    sender.send(1).unwrap();
+    drop(receiver);
+    assert_eq!(sender.send(2), Err(SendError(2)));
+
+    // only the element that made it into the queue is counted
+    assert!(!sender.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(sender.len(), 1);
+}
+
+#[test]
+fn custom_allocator() {
+    use core::sync::atomic::Ordering::Relaxed;
+
+    use crate::raw::CountingAlloc;
+
+    let alloc = CountingAlloc::default();
+    let (sender, receiver) =
+        channel_with_reclaimer_in(crate::reclaim::test_reclaimer(), alloc.clone());
+    for i in 0..10 {
+        sender.send(i).unwrap();
+    }
+    assert_eq!(receiver.try_recv(), Ok(0));
+
+    // the sentinel plus one node per send
+    assert_eq!(alloc.allocs.load(Relaxed), 11);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn recv_timeout() {
+    use std::thread;
+
+    let (sender, receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(10)),
+        Err(RecvTimeoutError::Timeout)
+    );
+
+    let handle = thread::spawn(move || {
+        let first = receiver.recv_timeout(Duration::from_secs(60));
+        let second = receiver.recv_timeout(Duration::from_secs(60));
+        (first, second)
+    });
+
+    thread::sleep(Duration::from_millis(10));
+    sender.send(1).unwrap();
+    thread::sleep(Duration::from_millis(10));
+    drop(sender);
+
+    let (first, second) = handle.join().unwrap();
+    assert_eq!(first, Ok(1));
+    assert_eq!(second, Err(RecvTimeoutError::Disconnected));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn stream_and_sink() {
+    use crate::waiter::block_on;
+    use std::thread;
+
+    let (mut sender, mut receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+
+    let handle = thread::spawn(move || {
+        for i in 0..100 {
+            Pin::new(&mut sender).start_send(i).unwrap();
+        }
+        block_on(future::poll_fn(|cx| Pin::new(&mut sender).poll_close(cx))).unwrap();
+        sender
+    });
+
+    // the stream ends once the sender is closed, even though it is still around
+    let mut sum = 0;
+    while let Some(i) = block_on(future::poll_fn(|cx| Pin::new(&mut receiver).poll_next(cx))) {
+        sum += i;
+    }
+    let mut sender = handle.join().unwrap();
+
+    assert_eq!(sum, 99 * 100 / 2);
+    assert_eq!(block_on(receiver.recv_async()), Err(RecvError));
+    assert_eq!(Pin::new(&mut sender).start_send(1), Err(SendError(1)));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const THREADS: usize = 4;
+
+    let (sender, receiver) = channel_with_reclaimer(crate::reclaim::test_reclaimer());
+
+    for _ in 0..THREADS {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for i in 0..RUNS {
+                sender.send(i).unwrap();
+            }
+        });
+    }
+    drop(sender);
+
+    let consumers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                let mut sum = 0;
+                while let Ok(i) = receiver.recv() {
+                    sum += i;
+                }
+                sum
+            })
+        })
+        .collect();
+
+    let sum: usize = consumers.into_iter().map(|c| c.join().unwrap()).sum();
+    assert_eq!(sum, THREADS * RUNS * (RUNS - 1) / 2);
+}
@@ -0,0 +1,54 @@
+#[cfg(feature = "counted")]
+use core::sync::atomic::{AtomicIsize, Ordering::Relaxed};
+
+#[cfg(feature = "counted")]
+use crate::cache_padded::CachePadded;
+
+/// How many elements a linked container holds, behind its `len`.
+///
+/// Only kept with the `counted` feature, without it this is empty and every
+/// update compiles away. Pushes and pops count themselves after they take
+/// effect, so a pop can get there before the push it took from, and the
+/// count may briefly dip below zero.
+pub(crate) struct Counter {
+    // every push and pop writes it, so it gets a line of its own
+    #[cfg(feature = "counted")]
+    count: CachePadded<AtomicIsize>,
+}
+
+impl Counter {
+    pub(crate) fn new() -> Counter {
+        Counter {
+            #[cfg(feature = "counted")]
+            count: CachePadded::new(AtomicIsize::new(0)),
+        }
+    }
+
+    #[cfg_attr(not(feature = "counted"), allow(unused_variables))]
+    pub(crate) fn add(&self, n: usize) {
+        #[cfg(feature = "counted")]
+        self.count.fetch_add(n as isize, Relaxed);
+    }
+
+    #[cfg_attr(not(feature = "counted"), allow(unused_variables))]
+    pub(crate) fn sub(&self, n: usize) {
+        #[cfg(feature = "counted")]
+        self.count.fetch_sub(n as isize, Relaxed);
+    }
+
+    #[cfg(feature = "counted")]
+    pub(crate) fn get(&self) -> usize {
+        self.count.load(Relaxed).max(0) as usize
+    }
+}
+
+#[cfg(feature = "counted")]
+#[test]
+fn pop_counted_before_its_push() {
+    let counter = Counter::new();
+
+    counter.sub(1);
+    assert_eq!(counter.get(), 0);
+    counter.add(1);
+    assert_eq!(counter.get(), 0);
+}
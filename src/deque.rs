@@ -0,0 +1,546 @@
+//! A Chase–Lev work-stealing deque.
+//!
+//! The owning [`Worker`] pushes and pops at the bottom like a stack, while any
+//! number of [`Stealer`]s take the oldest elements from the top. The buffer
+//! grows when full, and old buffers are freed through the reclaimer once no
+//! stealer can still be reading them.
+//!
+//! Stealers can also park until the worker pushes, through
+//! [`Stealer::steal_blocking`] and its timed variants. With `std` or `async`
+//! enabled every push pays a `SeqCst` fence and a load to see whether one is.
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+
+use crate::cache_padded::CachePadded;
+use crate::raw;
+#[cfg(test)]
+use crate::reclaim::test_reclaimer;
+use crate::reclaim::{DefaultReclaimer, Guard, Reclaimer};
+use crate::sync::atomic::{fence, AtomicIsize, AtomicPtr};
+#[cfg(feature = "std")]
+use crate::sync::spin_loop;
+use crate::waiter::Waiters;
+
+// capacity of the first buffer, must be a power of two
+const MIN_CAP: usize = 16;
+
+struct Inner<T, R, A: Allocator> {
+    // index of the oldest element, only ever moves forward
+    top: CachePadded<AtomicIsize>,
+    // index one past the newest element, only written by the worker
+    bottom: CachePadded<AtomicIsize>,
+    buffer: AtomicPtr<Buffer<T, A>>,
+    // stealers parked until the worker pushes
+    waiters: Waiters,
+    reclaimer: R,
+    alloc: A,
+}
+
+struct Buffer<T, A: Allocator> {
+    // stealers read slots the worker may be writing, which loom would flag,
+    // so these stay plain cells even under `cfg(loom)`
+    slots: Box<[UnsafeCell<MaybeUninit<T>>], A>,
+    // the reclaimer may free the buffer after the deque is gone, so it carries its own allocator
+    alloc: A,
+}
+
+/// The owning end of a deque, pushes and pops at the bottom.
+pub struct Worker<T, R = DefaultReclaimer, A: Allocator = Global> {
+    inner: Arc<Inner<T, R, A>>,
+    // only one thread may push and pop at a time
+    _marker: PhantomData<*mut ()>,
+}
+
+/// A handle that steals from the top of a [`Worker`]'s deque.
+pub struct Stealer<T, R = DefaultReclaimer, A: Allocator = Global> {
+    inner: Arc<Inner<T, R, A>>,
+}
+
+/// The outcome of a [`Stealer::steal`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    /// The deque was empty.
+    Empty,
+    /// An element was stolen.
+    Success(T),
+    /// Lost a race with another thread, trying again may succeed.
+    Retry,
+}
+
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync> Send for Worker<T, R, A> {}
+
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync> Send for Stealer<T, R, A> {}
+
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync> Sync for Stealer<T, R, A> {}
+
+impl<T, A: Allocator> Buffer<T, A> {
+    fn alloc(cap: usize, alloc: &A) -> *mut Buffer<T, A>
+    where
+        A: Clone,
+    {
+        let mut slots = Vec::with_capacity_in(cap, alloc.clone());
+        slots.extend((0..cap).map(|_| UnsafeCell::new(MaybeUninit::uninit())));
+
+        let buffer = Buffer {
+            slots: slots.into_boxed_slice(),
+            alloc: alloc.clone(),
+        };
+        raw::alloc_in(buffer, alloc)
+    }
+
+    fn cap(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: isize) -> *mut MaybeUninit<T> {
+        self.slots[index as usize & (self.cap() - 1)].get()
+    }
+
+    unsafe fn write(&self, index: isize, t: T) {
+        ptr::write_volatile(self.slot(index), MaybeUninit::new(t))
+    }
+
+    // a stealer may read a slot the worker is overwriting, in which case its
+    // CAS on `top` fails and the copy is thrown away, so never assume it is valid
+    unsafe fn read(&self, index: isize) -> MaybeUninit<T> {
+        ptr::read_volatile(self.slot(index))
+    }
+
+    // handed to `Guard::retire`, the slots are copies by then so only the memory goes
+    unsafe fn free(ptr: *mut u8) {
+        let buffer = ptr as *mut Buffer<T, A>;
+        let alloc = ptr::read(&(*buffer).alloc);
+        ptr::drop_in_place(&mut (*buffer).slots);
+        raw::dealloc_in(buffer, &alloc);
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Worker<T> {
+    pub fn new() -> Worker<T> {
+        Worker::with_reclaimer(DefaultReclaimer::default())
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T, A: Allocator + Clone + 'static> Worker<T, DefaultReclaimer, A> {
+    /// Creates a deque that allocates its buffers from `alloc`.
+    ///
+    /// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+    /// and how to use a short-lived arena anyway.
+    pub fn new_in(alloc: A) -> Worker<T, DefaultReclaimer, A> {
+        Worker::with_reclaimer_in(DefaultReclaimer::default(), alloc)
+    }
+}
+
+impl<T, R: Reclaimer> Worker<T, R> {
+    /// Creates a deque that frees its old buffers through `reclaimer`.
+    pub fn with_reclaimer(reclaimer: R) -> Worker<T, R> {
+        Worker::with_reclaimer_in(reclaimer, Global)
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Worker<T, R, A> {
+    /// Creates a deque that allocates its buffers from `alloc` and frees old ones through `reclaimer`.
+    ///
+    /// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+    /// and how to use a short-lived arena anyway.
+    pub fn with_reclaimer_in(reclaimer: R, alloc: A) -> Worker<T, R, A> {
+        Worker {
+            inner: Arc::new(Inner {
+                top: CachePadded::new(AtomicIsize::new(0)),
+                bottom: CachePadded::new(AtomicIsize::new(0)),
+                buffer: AtomicPtr::new(Buffer::alloc(MIN_CAP, &alloc)),
+                waiters: Waiters::new(),
+                reclaimer,
+                alloc,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// How many elements the deque holds, or more if stealers are taking some meanwhile.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stealer(&self) -> Stealer<T, R, A> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn push(&self, t: T) {
+        let inner = &*self.inner;
+
+        unsafe {
+            // only we ever replace the buffer, so it can't be freed under us
+            let mut buffer = inner.buffer.load(Relaxed);
+
+            let b = inner.bottom.load(Relaxed);
+            let top = inner.top.load(Acquire);
+            if b - top >= (*buffer).cap() as isize {
+                buffer = self.grow(buffer, top, b);
+            }
+
+            (*buffer).write(b, t);
+            fence(Release);
+            inner.bottom.store(b + 1, Relaxed);
+        }
+
+        inner.waiters.notify();
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+
+        unsafe {
+            let buffer = inner.buffer.load(Relaxed);
+
+            // reserve the bottom element before looking at `top`
+            let b = inner.bottom.load(Relaxed) - 1;
+            inner.bottom.store(b, Relaxed);
+            fence(SeqCst);
+            let top = inner.top.load(Relaxed);
+
+            if top > b {
+                // the deque was empty
+                inner.bottom.store(b + 1, Relaxed);
+                return None;
+            }
+
+            let data = (*buffer).read(b);
+            if top == b {
+                // last element, race the stealers for it
+                let won = inner
+                    .top
+                    .compare_exchange(top, top + 1, SeqCst, Relaxed)
+                    .is_ok();
+                inner.bottom.store(b + 1, Relaxed);
+                if !won {
+                    return None;
+                }
+            }
+
+            Some(data.assume_init())
+        }
+    }
+
+    // moves the live elements into a buffer twice the size and retires the old one
+    unsafe fn grow(&self, old: *mut Buffer<T, A>, top: isize, b: isize) -> *mut Buffer<T, A> {
+        let inner = &*self.inner;
+
+        let new = Buffer::alloc((*old).cap() * 2, &inner.alloc);
+        for i in top..b {
+            ptr::copy_nonoverlapping((*old).slot(i), (*new).slot(i), 1);
+        }
+
+        inner.buffer.store(new, Release);
+
+        // stealers may still be reading the old buffer
+        inner
+            .reclaimer
+            .pin()
+            .retire(old as *mut u8, Buffer::<T, A>::free);
+
+        new
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Default for Worker<T> {
+    fn default() -> Worker<T> {
+        Worker::new()
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator> Stealer<T, R, A> {
+    /// Roughly how many elements the deque holds, pushes, pops and steals
+    /// racing with this may or may not be counted.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &*self.inner;
+        let guard = inner.reclaimer.pin();
+
+        let top = inner.top.load(Acquire);
+        fence(SeqCst);
+        let b = inner.bottom.load(Acquire);
+        if top >= b {
+            return Steal::Empty;
+        }
+
+        // every buffer the worker installs holds the element at `top`
+        let buffer = guard.protect(0, &inner.buffer);
+        let data = unsafe { (*buffer).read(top) };
+
+        if inner
+            .top
+            .compare_exchange(top, top + 1, SeqCst, Relaxed)
+            .is_err()
+        {
+            return Steal::Retry;
+        }
+
+        Steal::Success(unsafe { data.assume_init() })
+    }
+
+    /// Steals an element, parking the thread until the worker pushes one if the deque is empty.
+    #[cfg(feature = "std")]
+    pub fn steal_blocking(&self) -> T {
+        self.inner
+            .waiters
+            .wait_until(None, || self.steal_retrying())
+            .unwrap()
+    }
+
+    /// Like [`Stealer::steal_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn steal_timeout(&self, timeout: Duration) -> Option<T> {
+        self.inner
+            .waiters
+            .wait_for(timeout, || self.steal_retrying())
+    }
+
+    /// Like [`Stealer::steal_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn steal_deadline(&self, deadline: Instant) -> Option<T> {
+        self.inner
+            .waiters
+            .wait_until(Some(deadline), || self.steal_retrying())
+    }
+
+    // steals until the deque is seen empty, a lost race doesn't mean there is nothing left
+    #[cfg(feature = "std")]
+    fn steal_retrying(&self) -> Option<T> {
+        loop {
+            match self.steal() {
+                Steal::Success(t) => return Some(t),
+                Steal::Empty => return None,
+                Steal::Retry => spin_loop(),
+            }
+        }
+    }
+}
+
+impl<T, R, A: Allocator> Clone for Stealer<T, R, A> {
+    fn clone(&self) -> Stealer<T, R, A> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, R, A: Allocator> Inner<T, R, A> {
+    fn len(&self) -> usize {
+        let top = self.top.load(Relaxed);
+        let b = self.bottom.load(Relaxed);
+
+        // a pop lowers `bottom` before it knows whether it wins, and may take it below `top` for a moment
+        (b - top).max(0) as usize
+    }
+}
+
+impl<T, R, A: Allocator> Drop for Inner<T, R, A> {
+    fn drop(&mut self) {
+        unsafe {
+            let buffer = self.buffer.load(Relaxed);
+            let top = self.top.load(Relaxed);
+            let b = self.bottom.load(Relaxed);
+
+            for i in top..b {
+                (*(*buffer).slot(i)).as_mut_ptr().drop_in_place();
+            }
+            Buffer::<T, A>::free(buffer as *mut u8);
+        }
+    }
+}
+
+#[test]
+fn push_items() {
+    let worker = Worker::with_reclaimer(test_reclaimer());
+    let stealer = worker.stealer();
+
+    worker.push(10);
+    worker.push(5);
+    worker.push(1);
+
+    // the worker pops the newest, stealers take the oldest
+    assert_eq!(worker.pop().unwrap(), 1);
+    assert_eq!(stealer.steal(), Steal::Success(10));
+    assert_eq!(worker.pop().unwrap(), 5);
+    assert!(worker.pop().is_none());
+    assert_eq!(stealer.steal(), Steal::Empty);
+}
+
+#[test]
+fn grows() {
+    let worker = Worker::with_reclaimer(test_reclaimer());
+    let stealer = worker.stealer();
+
+    for i in 0..MIN_CAP * 10 {
+        worker.push(i);
+    }
+    for i in 0..MIN_CAP {
+        assert_eq!(stealer.steal(), Steal::Success(i));
+    }
+    for i in (MIN_CAP..MIN_CAP * 10).rev() {
+        assert_eq!(worker.pop().unwrap(), i);
+    }
+    assert!(worker.pop().is_none());
+}
+
+#[test]
+fn drop_remaining() {
+    let item = Arc::new(());
+
+    let worker = Worker::with_reclaimer(test_reclaimer());
+    let stealer = worker.stealer();
+    for _ in 0..MIN_CAP * 2 {
+        worker.push(item.clone());
+    }
+    drop(worker.pop());
+    drop(stealer.steal());
+    drop(worker);
+    drop(stealer);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn len_after_racing_for_last() {
+    use std::sync::Barrier;
+    use std::thread;
+
+    const RUNS: usize = 1_000;
+
+    let worker = Worker::with_reclaimer(test_reclaimer());
+    let stealer = worker.stealer();
+    let barrier = Arc::new(Barrier::new(2));
+
+    let handle = thread::spawn({
+        let barrier = barrier.clone();
+        move || {
+            let mut stolen = 0;
+            for _ in 0..RUNS {
+                barrier.wait();
+                if let Steal::Success(_) = stealer.steal() {
+                    stolen += 1;
+                }
+                barrier.wait();
+            }
+            stolen
+        }
+    });
+
+    let mut popped = 0;
+    for _ in 0..RUNS {
+        worker.push(1);
+        barrier.wait();
+        if worker.pop().is_some() {
+            popped += 1;
+        }
+        barrier.wait();
+
+        // whoever lost, the pop must have put `bottom` back level with `top`
+        assert_eq!(worker.len(), 0);
+        assert!(worker.is_empty());
+    }
+
+    assert_eq!(popped + handle.join().unwrap(), RUNS);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn steal_timeout() {
+    use std::thread;
+    use std::time::Duration;
+
+    let worker = Worker::with_reclaimer(test_reclaimer());
+    let stealer = worker.stealer();
+    assert!(stealer.steal_timeout(Duration::from_millis(10)).is_none());
+
+    let handle = thread::spawn(move || stealer.steal_timeout(Duration::from_secs(60)));
+
+    thread::sleep(Duration::from_millis(10));
+    worker.push(1);
+
+    assert_eq!(handle.join().unwrap(), Some(1));
+}
+
+#[test]
+fn thread_test() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    const RUNS: usize = 100_000;
+    const STEALERS: usize = 4;
+
+    let worker = Worker::with_reclaimer(test_reclaimer());
+    let done = Arc::new(AtomicBool::new(false));
+
+    let stealers: Vec<_> = (0..STEALERS)
+        .map(|_| {
+            let stealer = worker.stealer();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut stolen = Vec::new();
+                loop {
+                    match stealer.steal() {
+                        Steal::Success(i) => stolen.push(i),
+                        Steal::Retry => {}
+                        Steal::Empty if done.load(Acquire) => return stolen,
+                        Steal::Empty => thread::yield_now(),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // every element must be taken exactly once, either by us or by a stealer
+    let mut seen = vec![false; RUNS];
+    for i in 0..RUNS {
+        worker.push(i);
+        if i % 3 == 0 {
+            if let Some(i) = worker.pop() {
+                assert!(!seen[i]);
+                seen[i] = true;
+            }
+        }
+    }
+    while let Some(i) = worker.pop() {
+        assert!(!seen[i]);
+        seen[i] = true;
+    }
+    done.store(true, Release);
+
+    for stealer in stealers {
+        for i in stealer.join().unwrap() {
+            assert!(!seen[i]);
+            seen[i] = true;
+        }
+    }
+    assert!(seen.into_iter().all(|seen| seen));
+}
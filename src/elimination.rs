@@ -0,0 +1,399 @@
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::mem::ManuallyDrop;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+
+use crate::backoff::{Backoff, Exponential};
+use crate::cache_padded::CachePadded;
+#[cfg(test)]
+use crate::reclaim::test_reclaimer;
+use crate::reclaim::{DefaultReclaimer, Reclaimer};
+use crate::sync::atomic::AtomicPtr;
+use crate::sync::spin_loop;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::{Node, Stack};
+
+// slots in the elimination array unless told otherwise
+#[cfg(any(feature = "flize", test))]
+const DEFAULT_WIDTH: usize = 8;
+
+// how many times a push offered to a pop checks for it before taking it back
+#[cfg(not(loom))]
+const PATIENCE: usize = 64;
+#[cfg(loom)]
+const PATIENCE: usize = 2;
+
+/// A [`Stack`] that pairs up pushes and pops colliding on its `head`.
+///
+/// A push whose CAS on `head` fails offers its node in a random slot of an
+/// elimination array, and a pop whose CAS fails looks in one for an offered
+/// node. When the two meet, the pop takes the pushed element and neither
+/// touches `head`, so contention spreads over the slots instead of retrying
+/// on a single word (Hendler, Shavit and Yerushalmi's elimination backoff).
+///
+/// A push or pop that loses on `head` and finds no partner pauses
+/// through the stack's [`Backoff`] before trying again.
+pub struct EliminationStack<T, R = DefaultReclaimer, A: Allocator = Global, B = Exponential> {
+    stack: Stack<T, R, A, B>,
+    slots: Box<[Slot<T, A>], A>,
+}
+
+// null when free, an offered node, or `taken()` until its pusher sees it went to a pop.
+// each on a line of its own, so offers in one slot don't slow down the others
+type Slot<T, A> = CachePadded<AtomicPtr<Node<T, A>>>;
+
+#[cfg(feature = "flize")]
+impl<T> EliminationStack<T> {
+    pub fn new() -> EliminationStack<T> {
+        EliminationStack::with_width(DEFAULT_WIDTH)
+    }
+
+    /// Creates a stack whose elimination array has `width` slots.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `width` is zero.
+    pub fn with_width(width: usize) -> EliminationStack<T> {
+        EliminationStack::from_stack(Stack::new(), width)
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static, B: Backoff> EliminationStack<T, R, A, B> {
+    /// Wraps `stack`, with `width` slots to pair up pushes and pops in.
+    ///
+    /// The stack's backoff, as set through [`Stack::with_backoff`], is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `width` is zero.
+    pub fn from_stack(stack: Stack<T, R, A, B>, width: usize) -> EliminationStack<T, R, A, B> {
+        assert!(width > 0, "width must be non-zero");
+
+        let mut slots = Vec::with_capacity_in(width, stack.alloc.clone());
+        slots.extend((0..width).map(|_| CachePadded::new(AtomicPtr::new(ptr::null_mut()))));
+
+        EliminationStack {
+            stack,
+            slots: slots.into_boxed_slice(),
+        }
+    }
+
+    /// Makes the stack pause through `backoff` whenever a CAS on `head` loses a race
+    /// and no partner turns up in the elimination array.
+    pub fn with_backoff<B2: Backoff>(self, backoff: B2) -> EliminationStack<T, R, A, B2> {
+        EliminationStack {
+            stack: self.stack.with_backoff(backoff),
+            slots: self.slots,
+        }
+    }
+
+    /// Pins the current thread, for use with [`EliminationStack::pop_with`] and
+    /// [`EliminationStack::push_with`].
+    pub fn pin(&self) -> R::Guard<'_> {
+        self.stack.pin()
+    }
+
+    pub fn push(&self, t: T) {
+        let n = Node::alloc(t, ptr::null_mut(), &self.stack.alloc);
+        let mut rng = Rng::new(n as usize);
+
+        let mut attempt = 0;
+        loop {
+            if self.stack.try_push_node(n) {
+                self.stack.waiters.notify();
+                return;
+            }
+
+            if self.offer(n, &mut rng) {
+                return;
+            }
+
+            self.stack.backoff.backoff(attempt);
+            attempt += 1;
+        }
+    }
+
+    /// Like [`EliminationStack::push`], taking a `guard` the caller already holds.
+    ///
+    /// As with [`Stack::push_with`], the push itself needs no protection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guard` was not pinned through this stack's reclaimer, or one sharing its state.
+    pub fn push_with(&self, t: T, guard: &R::Guard<'_>) {
+        assert!(
+            self.stack.reclaimer.owns(guard),
+            "guard belongs to a different reclaimer"
+        );
+
+        self.push(t);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.pop_with(&self.stack.pin())
+    }
+
+    /// Like [`EliminationStack::pop`], but protected by a `guard` the caller already holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guard` was not pinned through this stack's reclaimer, or one sharing its state.
+    pub fn pop_with(&self, guard: &R::Guard<'_>) -> Option<T> {
+        assert!(
+            self.stack.reclaimer.owns(guard),
+            "guard belongs to a different reclaimer"
+        );
+
+        let mut rng = Rng::new(guard as *const _ as usize);
+
+        let mut attempt = 0;
+        loop {
+            if let Ok(popped) = unsafe { self.stack.try_pop_with(guard) } {
+                return popped;
+            }
+
+            if let Some(t) = self.take(&mut rng) {
+                return Some(t);
+            }
+
+            self.stack.backoff.backoff(attempt);
+            attempt += 1;
+        }
+    }
+
+    // offers `n` to a pop in a random slot, true if one took it
+    fn offer(&self, n: *mut Node<T, A>, rng: &mut Rng) -> bool {
+        let slot = &self.slots[rng.below(self.slots.len())];
+
+        // publishes the node's contents to whichever pop takes it
+        if slot
+            .compare_exchange(ptr::null_mut(), n, Release, Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+
+        for _ in 0..PATIENCE {
+            if slot.load(Relaxed) == taken() {
+                break;
+            }
+            spin_loop();
+        }
+
+        // take the node back, unless a pop beat us to it
+        match slot.compare_exchange(n, ptr::null_mut(), Relaxed, Relaxed) {
+            Ok(_) => false,
+            Err(_) => {
+                // only we ever clear a taken slot, so nobody can offer in it meanwhile
+                slot.store(ptr::null_mut(), Relaxed);
+                true
+            }
+        }
+    }
+
+    // takes whatever a push offered in a random slot
+    fn take(&self, rng: &mut Rng) -> Option<T> {
+        let slot = &self.slots[rng.below(self.slots.len())];
+
+        let n = slot.load(Relaxed);
+        if n.is_null() || n == taken() {
+            return None;
+        }
+
+        // the offer is ours once we swap it out, and the pusher never looks at the node again
+        slot.compare_exchange(n, taken(), Acquire, Relaxed).ok()?;
+
+        unsafe {
+            // the node was never linked into the stack, so nobody else can be reading it
+            let data = (*n).data.with(|data| ptr::read(data));
+            Node::<T, A>::free(n as *mut u8);
+
+            Some(ManuallyDrop::into_inner(data))
+        }
+    }
+
+    /// Whether the stack is empty, which may no longer hold by the time this returns.
+    ///
+    /// Elements on their way from a push to a pop through the elimination
+    /// array are never in the stack, and don't count.
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// Roughly how many elements the stack holds, see [`Stack::len`].
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the stack is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&self) -> T {
+        self.stack.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`EliminationStack::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.stack.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`EliminationStack::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&self, deadline: Instant) -> Option<T> {
+        self.stack.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the stack is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&self) -> impl Future<Output = T> + '_ {
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| {
+            self.stack
+                .waiters
+                .poll_pop(cx, &mut registration, || self.pop())
+        })
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Default for EliminationStack<T> {
+    fn default() -> EliminationStack<T> {
+        EliminationStack::new()
+    }
+}
+
+// marks a slot whose node went to a pop, never a real node since those are never dangling
+fn taken<T, A>() -> *mut Node<T, A> {
+    NonNull::dangling().as_ptr()
+}
+
+// xorshift, seeded from an address so threads spread out over the slots
+struct Rng(usize);
+
+impl Rng {
+    fn new(seed: usize) -> Rng {
+        Rng(seed | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+#[test]
+fn push_items() {
+    let stack =
+        EliminationStack::from_stack(Stack::with_reclaimer(test_reclaimer()), DEFAULT_WIDTH);
+
+    stack.push(10);
+    stack.push(5);
+    stack.push(1);
+
+    assert_eq!(stack.pop().unwrap(), 1);
+    assert_eq!(stack.pop().unwrap(), 5);
+    assert_eq!(stack.pop().unwrap(), 10);
+    assert!(stack.pop().is_none());
+}
+
+#[test]
+fn drop_remaining() {
+    use std::sync::Arc;
+
+    let item = Arc::new(());
+
+    let stack =
+        EliminationStack::from_stack(Stack::with_reclaimer(test_reclaimer()), DEFAULT_WIDTH);
+    for _ in 0..10 {
+        stack.push(item.clone());
+    }
+    drop(stack.pop());
+    drop(stack);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[test]
+fn len_skips_eliminated() {
+    let stack = EliminationStack::from_stack(Stack::with_reclaimer(test_reclaimer()), 1);
+    stack.push(1);
+
+    // stand in for a push whose CAS on `head` lost, offering its node instead
+    let n = Node::alloc(2, ptr::null_mut(), &stack.stack.alloc);
+    stack.slots[0].store(n, Release);
+    #[cfg(feature = "counted")]
+    assert_eq!(stack.len(), 1);
+
+    // a pop takes it without either of them touching the stack, or its count
+    assert_eq!(stack.take(&mut Rng::new(1)), Some(2));
+    stack.slots[0].store(ptr::null_mut(), Relaxed);
+    assert!(!stack.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(stack.len(), 1);
+
+    assert_eq!(stack.pop(), Some(1));
+    assert!(stack.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(stack.len(), 0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn backs_off_on_lost_cas() {
+    use crate::backoff::{self, Counting};
+
+    let backoff = Counting::default();
+    let stack = EliminationStack::from_stack(Stack::with_reclaimer(test_reclaimer()), 1)
+        .with_backoff(backoff.clone());
+
+    // only once the elimination array turned up no partner either
+    backoff::collide(&backoff, || {
+        stack.push(1);
+        stack.pop();
+    });
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::sync::Arc;
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const THREADS: usize = 8;
+
+    // a single slot makes pushes and pops meet often
+    let stack = Arc::new(EliminationStack::from_stack(
+        Stack::with_reclaimer(test_reclaimer()),
+        1,
+    ));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = stack.clone();
+            thread::spawn(move || {
+                let mut sum = 0;
+                for i in 0..RUNS {
+                    our_copy.push(i);
+                    sum += our_copy.pop_blocking();
+                }
+                sum
+            })
+        })
+        .collect();
+
+    let sum: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(sum, THREADS * RUNS * (RUNS - 1) / 2);
+    assert!(stack.pop().is_none());
+}
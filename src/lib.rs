@@ -1,82 +1,471 @@
-use std::ptr;
-use std::sync::atomic::Ordering::{Acquire, Relaxed};
+//! Lock-free stacks, queues, deques and channels.
+//!
+//! # Allocators
+//!
+//! Every container can allocate its nodes or buffers from an [`Allocator`]
+//! handed to its `new_in` or `with_reclaimer_in`.
+//!
+//! [`Stack`], [`EliminationStack`], [`Queue`], the [`channel`] and the
+//! [`deque`] free what they unlink through a [`Reclaimer`], which may only get
+//! to it after the container itself is gone. Their allocator therefore has to
+//! be `'static`, and every node or buffer keeps a clone of it to be freed
+//! through. A borrowed arena, such as a per-frame bump allocator behind a
+//! `&'frame` reference, can't be used with them.
+//!
+//! An arena that only lives for a while can still be used through a handle
+//! that keeps it alive, like an `Arc` around it. Every node then clones the
+//! handle, which costs an atomic increment per allocation and a decrement per
+//! free. A `&'static` reference to an arena is copied for free instead.
+//!
+//! Either way, the arena's memory is only reclaimed once the container and
+//! every node it retired are gone, which with a deferred reclaimer can be well
+//! after the container is dropped.
+//!
+//! [`ArrayQueue`], the [`spsc`] ring buffer and the [`mpsc`] queues free
+//! everything themselves, and take any allocator.
 
-use flize::{Atomic, Collector, Shared, Shield};
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-pub struct Stack<T> {
-    head: Atomic<Node<T>>,
-    collector: Collector,
+extern crate alloc;
+
+#[cfg(feature = "flize")]
+use alloc::sync::Arc;
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::mem::ManuallyDrop;
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::backoff::{Backoff, Exponential};
+use crate::cache_padded::CachePadded;
+use crate::counter::Counter;
+#[cfg(test)]
+use crate::reclaim::test_reclaimer;
+#[cfg(feature = "flize")]
+use crate::reclaim::{Collector, Flize};
+use crate::reclaim::{DefaultReclaimer, Guard, Reclaimer};
+use crate::sync::atomic::AtomicPtr;
+use crate::sync::UnsafeCell;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::waiter::Waiters;
+
+mod array_queue;
+mod cache_padded;
+mod counter;
+mod elimination;
+mod queue;
+mod raw;
+mod sync;
+mod waiter;
+
+pub mod backoff;
+pub mod channel;
+pub mod deque;
+pub mod mpsc;
+#[cfg(feature = "pool")]
+pub mod pool;
+pub mod reclaim;
+pub mod spsc;
+
+pub use allocator_api2::alloc::{Allocator, Global};
+pub use array_queue::ArrayQueue;
+pub use elimination::EliminationStack;
+pub use queue::Queue;
+
+pub struct Stack<T, R = DefaultReclaimer, A: Allocator = Global, B = Exponential> {
+    head: CachePadded<AtomicPtr<Node<T, A>>>,
+    reclaimer: R,
+    alloc: A,
+    waiters: Waiters,
+    len: Counter,
+    backoff: B,
 }
 
-unsafe impl<T> Send for Stack<T> {}
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync, B: Send> Send for Stack<T, R, A, B> {}
 
-unsafe impl<T> Sync for Stack<T> {}
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync, B: Sync> Sync for Stack<T, R, A, B> {}
 
-struct Node<T> {
-    data: T,
-    next: Atomic<Node<T>>,
+struct Node<T, A> {
+    // popping moves the data out before the node is freed, so the node must not drop it
+    data: UnsafeCell<ManuallyDrop<T>>,
+    next: AtomicPtr<Node<T, A>>,
+    // the reclaimer may free the node after the stack is gone, so it carries its own allocator
+    alloc: A,
 }
 
+impl<T, A: Allocator> Node<T, A> {
+    fn alloc(t: T, next: *mut Node<T, A>, alloc: &A) -> *mut Node<T, A>
+    where
+        A: Clone,
+    {
+        let node = Node {
+            data: UnsafeCell::new(ManuallyDrop::new(t)),
+            next: AtomicPtr::new(next),
+            alloc: alloc.clone(),
+        };
+
+        raw::alloc_in(node, alloc)
+    }
+
+    // handed to `Guard::retire`, the data has been moved out by then
+    unsafe fn free(ptr: *mut u8) {
+        let node = ptr as *mut Node<T, A>;
+        let alloc = ptr::read(&(*node).alloc);
+        raw::dealloc_in(node, &alloc);
+    }
+}
+
+#[cfg(feature = "flize")]
 impl<T> Stack<T> {
     pub fn new() -> Stack<T> {
+        Stack::with_reclaimer(DefaultReclaimer::default())
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T, A: Allocator + Clone + 'static> Stack<T, DefaultReclaimer, A> {
+    /// Creates a stack that allocates its nodes from `alloc`.
+    ///
+    /// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+    /// and how to use a short-lived arena anyway.
+    pub fn new_in(alloc: A) -> Stack<T, DefaultReclaimer, A> {
+        Stack::with_reclaimer_in(DefaultReclaimer::default(), alloc)
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Stack<T, Flize> {
+    /// Creates a stack that retires its nodes through `collector`, which may be shared with other containers.
+    pub fn with_collector(collector: &Arc<Collector>) -> Stack<T, Flize> {
+        Stack::with_reclaimer(Flize::with_collector(collector))
+    }
+}
+
+impl<T, R: Reclaimer> Stack<T, R> {
+    /// Creates a stack that frees its nodes through `reclaimer`.
+    pub fn with_reclaimer(reclaimer: R) -> Stack<T, R> {
+        Stack::with_reclaimer_in(reclaimer, Global)
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Stack<T, R, A> {
+    /// Creates a stack that allocates its nodes from `alloc` and frees them through `reclaimer`.
+    ///
+    /// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+    /// and how to use a short-lived arena anyway.
+    pub fn with_reclaimer_in(reclaimer: R, alloc: A) -> Stack<T, R, A> {
         Stack {
-            head: Atomic::null(),
-            collector: Collector::new(),
+            head: CachePadded::new(AtomicPtr::new(ptr::null_mut())),
+            reclaimer,
+            alloc,
+            waiters: Waiters::new(),
+            len: Counter::new(),
+            backoff: Exponential::default(),
         }
     }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static, B: Backoff> Stack<T, R, A, B> {
+    /// Makes the stack pause through `backoff` whenever a CAS on `head` loses a race.
+    pub fn with_backoff<B2: Backoff>(self, backoff: B2) -> Stack<T, R, A, B2> {
+        let this = ManuallyDrop::new(self);
+
+        // move everything over, `this` is never dropped so the nodes stay ours
+        unsafe {
+            drop(ptr::read(&this.backoff));
+            Stack {
+                head: ptr::read(&this.head),
+                reclaimer: ptr::read(&this.reclaimer),
+                alloc: ptr::read(&this.alloc),
+                waiters: ptr::read(&this.waiters),
+                len: ptr::read(&this.len),
+                backoff,
+            }
+        }
+    }
+
+    /// Pins the current thread, for use with [`Stack::pop_with`] and [`Stack::push_with`].
+    pub fn pin(&self) -> R::Guard<'_> {
+        self.reclaimer.pin()
+    }
 
     pub fn pop(&self) -> Option<T> {
-        let guard = self.collector.thin_shield();
+        self.pop_with(&self.reclaimer.pin())
+    }
 
-        loop {
-            unsafe {
-                let head = self.head.load(Acquire, &guard);
-                if head.is_null() {
-                    return None;
-                }
+    /// Like [`Stack::pop`], but protected by a `guard` the caller already holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guard` was not pinned through this stack's reclaimer, or one sharing its state.
+    pub fn pop_with(&self, guard: &R::Guard<'_>) -> Option<T> {
+        assert!(
+            self.reclaimer.owns(guard),
+            "guard belongs to a different reclaimer"
+        );
 
-                let next = head.as_ref_unchecked().next.load(Relaxed, &guard);
-
-                // if snapshot is still good, update from `head` to `next`
-                if self
-                    .head
-                    .compare_exchange(head, next, Acquire, Relaxed, &guard)
-                    .is_ok()
-                {
-                    guard.retire(move || drop(head));
-                    // extract out the data from the now-unlinked node
-                    return Some(ptr::read(&(*head.as_ref_unchecked()).data));
-                }
+        let mut attempt = 0;
+        loop {
+            if let Ok(popped) = unsafe { self.try_pop_with(guard) } {
+                return popped;
             }
+            self.backoff.backoff(attempt);
+            attempt += 1;
         }
     }
 
+    // one attempt at unlinking the top node, `Err` if another thread moved `head` first
+    //
+    // `guard` must belong to this stack's reclaimer
+    unsafe fn try_pop_with(&self, guard: &R::Guard<'_>) -> Result<Option<T>, ()> {
+        let head = guard.protect(0, &self.head);
+        if head.is_null() {
+            return Ok(None);
+        }
+
+        // `protect` acquired the pusher's release of `head`, and `next` is
+        // never written once the node is linked
+        let next = (*head).next.load(Relaxed);
+
+        // if snapshot is still good, update from `head` to `next`
+        if self
+            .head
+            .compare_exchange(head, next, Acquire, Relaxed)
+            .is_err()
+        {
+            return Err(());
+        }
+
+        self.len.sub(1);
+
+        // extract out the data from the now-unlinked node
+        let data = (*head).data.with(|data| ptr::read(data));
+
+        // free the node once no other thread can still be reading it
+        guard.retire(head as *mut u8, Node::<T, A>::free);
+
+        Ok(Some(ManuallyDrop::into_inner(data)))
+    }
+
     pub fn push(&self, t: T) {
         // allocate the node, and immediately turn it into a *mut pointer
-        let guard = self.collector.thin_shield();
+        let n = Node::alloc(t, ptr::null_mut(), &self.alloc);
 
-        let mut n = unsafe {
-            Shared::from_ptr(Box::into_raw(Box::new(Node {
-                data: t,
-                next: Atomic::null(),
-            })))
-        };
-        loop {
-            // snapshot current head
-            let head = self.head.load(Relaxed, &guard);
+        let mut attempt = 0;
+        while !self.try_push_node(n) {
+            self.backoff.backoff(attempt);
+            attempt += 1;
+        }
+
+        self.waiters.notify();
+    }
+
+    /// Like [`Stack::push`], taking a `guard` the caller already holds.
+    ///
+    /// A push never reads a node another thread may free, so it needs no
+    /// protection of its own. This lets one guard drive a batch of pushes and
+    /// pops across stacks and queues sharing a reclaimer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guard` was not pinned through this stack's reclaimer, or one sharing its state.
+    pub fn push_with(&self, t: T, guard: &R::Guard<'_>) {
+        assert!(
+            self.reclaimer.owns(guard),
+            "guard belongs to a different reclaimer"
+        );
+
+        self.push(t);
+    }
 
-            // update `next` pointer with snapshot
+    // one attempt at linking `n` in on top, fails if another thread moved `head` first
+    fn try_push_node(&self, n: *mut Node<T, A>) -> bool {
+        // snapshot current head
+        let head = self.head.load(Relaxed);
+
+        // update `next` pointer with snapshot
+        unsafe {
+            (*n).next.store(head, Relaxed);
+        }
+
+        // if snapshot is still good, link in new node, publishing its contents
+        let linked = self
+            .head
+            .compare_exchange(head, n, Release, Relaxed)
+            .is_ok();
+        if linked {
+            self.len.add(1);
+        }
+        linked
+    }
+
+    /// Pushes every element of `iter` with a single CAS on `head`, the last one ending up on top.
+    pub fn push_all<I: IntoIterator<Item = T>>(&self, iter: I) {
+        // link the chain up front, `first` ends up at the bottom and `last` on top
+        let mut first: *mut Node<T, A> = ptr::null_mut();
+        let mut last = ptr::null_mut();
+        let mut count = 0;
+        for t in iter {
+            let n = Node::alloc(t, last, &self.alloc);
+            if first.is_null() {
+                first = n;
+            }
+            last = n;
+            count += 1;
+        }
+
+        if first.is_null() {
+            return;
+        }
+
+        let mut attempt = 0;
+        loop {
+            // snapshot current head and hang the chain off it
+            let head = self.head.load(Relaxed);
             unsafe {
-                n.as_ref_unchecked().next.store(head, Relaxed);
+                (*first).next.store(head, Relaxed);
             }
 
-            // if snapshot is still good, link in new node
-            match self
+            if self
                 .head
-                .compare_exchange(head, n, Acquire, Relaxed, &guard)
+                .compare_exchange(head, last, Release, Relaxed)
+                .is_ok()
             {
-                Ok(_) => return,
-                Err(owned) => n = owned,
+                break;
+            }
+            self.backoff.backoff(attempt);
+            attempt += 1;
+        }
+
+        self.len.add(count);
+        self.waiters.notify_all();
+    }
+
+    /// Detaches every element with a single swap on `head`, yielding them from the top down.
+    pub fn take_all(&self) -> TakeAll<'_, T, R, A> {
+        let head = self.head.swap(ptr::null_mut(), Acquire);
+
+        TakeAll {
+            first: head,
+            node: head,
+            reclaimer: &self.reclaimer,
+            len: &self.len,
+        }
+    }
+
+    /// Whether the stack is empty, which may no longer hold by the time this returns.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Relaxed).is_null()
+    }
+
+    /// Roughly how many elements the stack holds.
+    ///
+    /// Pushes and pops racing with this may or may not be counted, but the
+    /// count settles on the exact length once they are done. Detaching with
+    /// [`Stack::take_all`] only counts elements out as the iterator yields them.
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the stack is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&self) -> T {
+        self.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`Stack::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`Stack::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&self, deadline: Instant) -> Option<T> {
+        self.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the stack is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&self) -> impl Future<Output = T> + '_ {
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| self.waiters.poll_pop(cx, &mut registration, || self.pop()))
+    }
+}
+
+/// An iterator over the elements detached by [`Stack::take_all`].
+///
+/// Poppers that lost the race may still be reading the detached nodes, so they
+/// are retired through the reclaimer once this is dropped, under a pin taken
+/// just for that. The thread isn't pinned while iterating.
+pub struct TakeAll<'a, T, R: Reclaimer + 'a, A: Allocator = Global> {
+    // the detached chain, kept whole until we retire it
+    first: *mut Node<T, A>,
+    // the next node to yield
+    node: *mut Node<T, A>,
+    reclaimer: &'a R,
+    len: &'a Counter,
+}
+
+impl<'a, T, R: Reclaimer, A: Allocator> Iterator for TakeAll<'a, T, R, A> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.node.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let node = self.node;
+            self.node = (*node).next.load(Relaxed);
+            self.len.sub(1);
+
+            // nobody else can pop from the detached chain, so the data is ours to take
+            let data = (*node).data.with(|data| ptr::read(data));
+
+            Some(ManuallyDrop::into_inner(data))
+        }
+    }
+}
+
+impl<'a, T, R: Reclaimer, A: Allocator> Drop for TakeAll<'a, T, R, A> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+
+        // every element has been moved out, only the nodes are left
+        let guard = self.reclaimer.pin();
+        let mut node = self.first;
+        while !node.is_null() {
+            unsafe {
+                let next = (*node).next.load(Relaxed);
+                guard.retire(node as *mut u8, Node::<T, A>::free);
+                node = next;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Default for Stack<T> {
+    fn default() -> Stack<T> {
+        Stack::new()
+    }
+}
+
+impl<T, R, A: Allocator, B> Drop for Stack<T, R, A, B> {
+    fn drop(&mut self) {
+        unsafe {
+            // we have `&mut self`, so nobody else can be looking at the nodes
+            let mut node = self.head.load(Relaxed);
+            while !node.is_null() {
+                let next = (*node).next.load(Relaxed);
+                (*node).data.with_mut(|data| ManuallyDrop::drop(&mut *data));
+                Node::<T, A>::free(node as *mut u8);
+                node = next;
             }
         }
     }
@@ -84,7 +473,7 @@ impl<T> Stack<T> {
 
 #[test]
 fn push_items() {
-    let stack = Stack::new();
+    let stack = Stack::with_reclaimer(test_reclaimer());
 
     stack.push(10);
     stack.push(5);
@@ -95,14 +484,407 @@ fn push_items() {
     assert_eq!(stack.pop().unwrap(), 10);
 }
 
+// hammers a stack from a few threads, every pushed element must come out exactly once
+#[cfg(all(
+    test,
+    any(feature = "crossbeam-epoch", feature = "hazard", feature = "leak")
+))]
+fn reclaimer_test<R: Reclaimer + 'static>(reclaimer: R) {
+    use std::sync::Arc;
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const THREADS: usize = 4;
+
+    let stack = Arc::new(Stack::with_reclaimer(reclaimer));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = stack.clone();
+            thread::spawn(move || {
+                let mut sum = 0;
+                for i in 0..RUNS {
+                    our_copy.push(i);
+                    sum += our_copy.pop().unwrap();
+                }
+                sum
+            })
+        })
+        .collect();
+
+    let sum: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(sum, THREADS * RUNS * (RUNS - 1) / 2);
+    assert!(stack.pop().is_none());
+}
+
+#[cfg(feature = "crossbeam-epoch")]
 #[test]
-fn single_run() {
-    use std::time;
+fn crossbeam_epoch() {
+    reclaimer_test(reclaim::CrossbeamEpoch::new());
+}
+
+#[cfg(feature = "hazard")]
+#[test]
+fn hazard_pointers() {
+    reclaimer_test(reclaim::HazardPointers::with_scan_threshold(8));
+}
+
+#[cfg(feature = "leak")]
+#[test]
+fn leak() {
+    reclaimer_test(reclaim::Leak::new());
+}
+
+// the same hammering, with every backoff strategy
+#[cfg(feature = "std")]
+#[test]
+fn backoff_strategies() {
+    use std::sync::Arc;
+    use std::thread;
+
+    fn hammer<B: Backoff + Send + Sync + 'static>(backoff: B) {
+        const RUNS: usize = 10_000;
+        const THREADS: usize = 4;
+
+        let stack = Arc::new(Stack::with_reclaimer(test_reclaimer()).with_backoff(backoff));
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let our_copy = stack.clone();
+                thread::spawn(move || {
+                    let mut sum = 0;
+                    for i in 0..RUNS {
+                        our_copy.push(i);
+                        sum += our_copy.pop().unwrap();
+                    }
+                    sum
+                })
+            })
+            .collect();
+
+        let sum: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(sum, THREADS * RUNS * (RUNS - 1) / 2);
+        assert!(stack.pop().is_none());
+        // every push and pop is done, so the count has settled
+        #[cfg(feature = "counted")]
+        assert_eq!(stack.len(), 0);
+    }
+
+    hammer(backoff::Spin);
+    hammer(backoff::Exponential::new(3));
+    hammer(backoff::Yield);
+    hammer(backoff::Adaptive::new(2));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn backs_off_on_lost_cas() {
+    let backoff = backoff::Counting::default();
+    let stack = Stack::with_reclaimer(test_reclaimer()).with_backoff(backoff.clone());
+
+    backoff::collide(&backoff, || {
+        stack.push(1);
+        stack.pop();
+    });
+}
+
+#[test]
+fn with_backoff_keeps_elements() {
+    use std::sync::Arc;
+
+    let item = Arc::new(());
+
+    let stack = Stack::with_reclaimer(test_reclaimer());
+    stack.push(item.clone());
+    stack.push(item.clone());
+
+    let stack = stack.with_backoff(backoff::Spin);
+    assert!(stack.pop().is_some());
+    drop(stack);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[test]
+fn custom_allocator() {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    let alloc = raw::CountingAlloc::default();
+    let stack = Stack::with_reclaimer_in(test_reclaimer(), alloc.clone());
+
+    stack.push_all(0..10);
+    stack.push(10);
+    assert_eq!(stack.pop(), Some(10));
+    assert_eq!(stack.pop(), Some(9));
+    assert_eq!(alloc.allocs.load(Relaxed), 11);
+
+    // the nodes still in the stack go back right away, popped ones whenever the reclaimer lets them
+    drop(stack);
+    assert!(alloc.live.load(Relaxed) <= 2);
+}
+
+#[test]
+fn frame_arena() {
+    use std::alloc::Layout;
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::sync::Arc;
+
+    use allocator_api2::alloc::AllocError;
+
+    // a bump arena that frees everything at once when dropped
+    struct Arena {
+        buf: Box<[u64]>,
+        used: AtomicUsize,
+    }
+
+    // the handle containers get, `'static` however long the arena lives
+    #[derive(Clone)]
+    struct FrameArena(Arc<Arena>);
+
+    impl FrameArena {
+        fn new() -> FrameArena {
+            FrameArena(Arc::new(Arena {
+                buf: vec![0; 512].into_boxed_slice(),
+                used: AtomicUsize::new(0),
+            }))
+        }
+    }
+
+    unsafe impl Allocator for FrameArena {
+        fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+            assert!(layout.align() <= 8);
+            let size = layout.size().next_multiple_of(8);
+            let start = self.0.used.fetch_add(size, Relaxed);
+            if start + size > self.0.buf.len() * 8 {
+                return Err(AllocError);
+            }
+
+            let ptr = self.0.buf.as_ptr() as *mut u8;
+            let ptr = unsafe { ptr::NonNull::new_unchecked(ptr.add(start)) };
+            Ok(ptr::NonNull::slice_from_raw_parts(ptr, size))
+        }
+
+        unsafe fn deallocate(&self, _ptr: ptr::NonNull<u8>, _layout: Layout) {}
+    }
+
+    for frame in 0..3 {
+        let arena = FrameArena::new();
+        let weak = Arc::downgrade(&arena.0);
+
+        let stack = Stack::with_reclaimer_in(test_reclaimer(), arena);
+        stack.push_all(0..10);
+        assert_eq!(stack.pop(), Some(9));
+        assert_eq!(stack.pop(), Some(8));
+        assert!(weak.upgrade().unwrap().used.load(Relaxed) > 0);
+
+        // nodes still in the stack let go of the arena with it, popped ones
+        // whenever the reclaimer frees them
+        drop(stack);
+        assert!(weak.strong_count() <= 2, "frame {}", frame);
+    }
+
+    // with nothing popped, the arena goes away along with the stack
+    let arena = FrameArena::new();
+    let weak = Arc::downgrade(&arena.0);
+    let stack = Stack::with_reclaimer_in(test_reclaimer(), arena);
+    stack.push_all(0..10);
+    drop(stack);
+    assert!(weak.upgrade().is_none());
+}
+
+#[cfg(feature = "flize")]
+#[test]
+fn shared_collector() {
+    let collector = Arc::new(Collector::new());
+    let first = Stack::with_collector(&collector);
+    let second = Stack::with_collector(&collector);
+    let queue = Queue::with_collector(&collector);
+    let elimination = EliminationStack::from_stack(Stack::with_collector(&collector), 1);
+
+    // one guard covers every container sharing the collector
+    let guard = first.pin();
+    first.push_with(1, &guard);
+    second.push_with(2, &guard);
+    queue.push_with(3, &guard);
+    elimination.push_with(4, &guard);
+
+    assert_eq!(first.pop_with(&guard), Some(1));
+    assert_eq!(second.pop_with(&guard), Some(2));
+    assert_eq!(queue.pop_with(&guard), Some(3));
+    assert_eq!(elimination.pop_with(&guard), Some(4));
+    assert_eq!(second.pop_with(&guard), None);
+}
+
+#[cfg(feature = "flize")]
+#[test]
+#[should_panic(expected = "guard belongs to a different reclaimer")]
+fn foreign_guard() {
+    let first: Stack<u8> = Stack::new();
+    let second: Stack<u8> = Stack::new();
+
+    first.pop_with(&second.pin());
+}
+
+#[cfg(feature = "flize")]
+#[test]
+#[should_panic(expected = "guard belongs to a different reclaimer")]
+fn foreign_guard_push() {
+    let first: Stack<u8> = Stack::new();
+    let second: Stack<u8> = Stack::new();
+
+    first.push_with(1, &second.pin());
+}
+
+#[test]
+fn drop_remaining() {
+    use std::sync::Arc;
+
+    let item = Arc::new(());
+
+    let stack = Stack::with_reclaimer(test_reclaimer());
+    for _ in 0..10 {
+        stack.push(item.clone());
+    }
+    drop(stack);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[cfg(feature = "flize")]
+#[test]
+fn pop_frees_nodes() {
+    use flize::Shield;
+
+    use std::mem;
+    use std::sync::Arc;
+
+    // large enough that nothing but these nodes shares their allocation size
+    struct Payload {
+        _pad: [u8; 3000],
+        _item: Arc<()>,
+    }
+
+    const RUNS: usize = 1_000;
+    let size = mem::size_of::<Node<Payload, Global>>();
+
+    let item = Arc::new(());
+    let stack = Stack::with_reclaimer(reclaim::Flize::new());
+
+    let allocs = counting_alloc::allocs(size);
+    let frees = counting_alloc::frees(size);
+
+    for _ in 0..RUNS {
+        stack.push(Payload {
+            _pad: [0; 3000],
+            _item: item.clone(),
+        });
+    }
+    for _ in 0..RUNS {
+        drop(stack.pop().unwrap());
+    }
+    assert_eq!(counting_alloc::allocs(size) - allocs, RUNS);
+
+    // push any partially filled bag to the collector and run it until everything retired is freed
+    let collector = &stack.reclaimer.collector;
+    collector.thin_shield().flush();
+    for _ in 0..10 {
+        if counting_alloc::frees(size) - frees == RUNS {
+            break;
+        }
+        let _ = collector.try_collect_light();
+    }
+
+    assert_eq!(counting_alloc::frees(size) - frees, RUNS);
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[test]
+fn push_all_take_all() {
+    let stack = Stack::with_reclaimer(test_reclaimer());
+
+    stack.push(1);
+    stack.push_all(vec![2, 3, 4]);
+    stack.push_all(Vec::new());
+    assert_eq!(stack.pop().unwrap(), 4);
+
+    assert_eq!(stack.take_all().collect::<Vec<_>>(), [3, 2, 1]);
+    assert!(stack.pop().is_none());
+    assert_eq!(stack.take_all().next(), None);
+}
+
+#[test]
+fn take_all_drops_remaining() {
+    use std::sync::Arc;
+
+    let item = Arc::new(());
 
-    let stack = Stack::new();
-    let now = time::Instant::now();
+    let stack = Stack::with_reclaimer(test_reclaimer());
+    stack.push_all((0..10).map(|_| item.clone()));
 
-    const RUNS: i32 = 10_000_000;
+    let mut taken = stack.take_all();
+    drop(taken.next());
+    drop(taken);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn push_all_wakes_every_popper() {
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 4;
+
+    let stack = Arc::new(Stack::with_reclaimer(test_reclaimer()));
+
+    let poppers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = stack.clone();
+            thread::spawn(move || our_copy.pop_timeout(Duration::from_secs(10)))
+        })
+        .collect();
+
+    // let them park first, a single wakeup would leave all but one asleep until they time out
+    thread::sleep(Duration::from_millis(50));
+    let pushed = Instant::now();
+    stack.push_all(0..THREADS);
+
+    for popper in poppers {
+        assert!(popper.join().unwrap().is_some());
+    }
+    assert!(pushed.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn len_counts_take_all() {
+    let stack = Stack::with_reclaimer(test_reclaimer());
+    assert!(stack.is_empty());
+
+    stack.push(1);
+    stack.push_all([2, 3, 4]);
+    assert!(!stack.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(stack.len(), 4);
+
+    stack.pop();
+    let mut taken = stack.take_all();
+    taken.next();
+    assert!(stack.is_empty());
+    // the detached elements leave the count as they are yielded
+    #[cfg(feature = "counted")]
+    assert_eq!(stack.len(), 2);
+
+    drop(taken);
+    #[cfg(feature = "counted")]
+    assert_eq!(stack.len(), 0);
+}
+
+#[test]
+fn single_run() {
+    let stack = Stack::with_reclaimer(test_reclaimer());
+
+    const RUNS: i32 = 100_000;
 
     for _i in 0..RUNS {
         stack.push(11);
@@ -111,24 +893,18 @@ fn single_run() {
     for _i in 0..RUNS {
         assert_eq!(stack.pop().unwrap(), 11);
     }
-
-    println!(
-        "It took {:?} to write and read {} messages",
-        now.elapsed(),
-        RUNS
-    );
+    assert!(stack.pop().is_none());
 }
 
+#[cfg(feature = "std")]
 #[test]
 fn thread_test() {
     use std::sync::Arc;
-    use std::{thread, time};
+    use std::thread;
 
     const RUNS: i32 = 1;
 
-    let stack = Arc::new(Stack::new());
-
-    let now = time::Instant::now();
+    let stack = Arc::new(Stack::with_reclaimer(test_reclaimer()));
 
     let our_copy = stack.clone();
     thread::spawn(move || {
@@ -144,19 +920,130 @@ fn thread_test() {
         }
     });
 
-    let wait = time::Duration::from_millis(1);
-    thread::sleep(wait);
-
     let mut count = 0;
     for _ in 0..RUNS * 2 {
-        count += stack.pop().unwrap();
+        count += stack.pop_blocking();
     }
 
-    println!(
-        "It took {:?} to write and read {} messages",
-        now.elapsed(),
-        RUNS * 2
-    );
-
     assert_eq!(count, RUNS * 2)
 }
+
+#[cfg(feature = "std")]
+#[test]
+fn pop_timeout() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let stack = Arc::new(Stack::with_reclaimer(test_reclaimer()));
+    assert!(stack.pop_timeout(Duration::from_millis(10)).is_none());
+
+    let our_copy = stack.clone();
+    let handle = thread::spawn(move || our_copy.pop_timeout(Duration::from_secs(60)));
+
+    thread::sleep(Duration::from_millis(10));
+    stack.push(1);
+
+    assert_eq!(handle.join().unwrap(), Some(1));
+}
+
+#[test]
+fn batch_thread_test() {
+    use std::sync::Arc;
+    use std::thread;
+
+    const RUNS: usize = 1_000;
+    const BATCH: usize = 10;
+    const THREADS: usize = 4;
+
+    let stack = Arc::new(Stack::with_reclaimer(test_reclaimer()));
+
+    let producers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = stack.clone();
+            thread::spawn(move || {
+                for i in 0..RUNS {
+                    our_copy.push_all((0..BATCH).map(|j| i * BATCH + j));
+                }
+            })
+        })
+        .collect();
+
+    let mut seen = 0;
+    let mut sum = 0;
+    while seen < RUNS * BATCH * THREADS {
+        for i in stack.take_all() {
+            seen += 1;
+            sum += i;
+        }
+    }
+    for producer in producers {
+        producer.join().unwrap();
+    }
+
+    let total = RUNS * BATCH;
+    assert_eq!(sum, THREADS * total * (total - 1) / 2);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn pop_async() {
+    use crate::waiter::block_on;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let stack = Arc::new(Stack::with_reclaimer(test_reclaimer()));
+
+    let our_copy = stack.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        our_copy.push(1);
+    });
+
+    assert_eq!(block_on(stack.pop_async()), 1);
+    handle.join().unwrap();
+}
+
+// only the flize tests can force a collection, so only they look at the counts
+#[cfg(all(test, feature = "flize"))]
+#[global_allocator]
+static ALLOCATOR: counting_alloc::CountingAlloc = counting_alloc::CountingAlloc;
+
+/// Counts allocations by size, so tests can check that nodes of a given layout are not leaked.
+#[cfg(all(test, feature = "flize"))]
+mod counting_alloc {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+    // sizes at or above this are not tracked
+    const BUCKETS: usize = 4096;
+
+    static ALLOCS: [AtomicUsize; BUCKETS] = [const { AtomicUsize::new(0) }; BUCKETS];
+    static FREES: [AtomicUsize; BUCKETS] = [const { AtomicUsize::new(0) }; BUCKETS];
+
+    pub struct CountingAlloc;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if let Some(count) = ALLOCS.get(layout.size()) {
+                count.fetch_add(1, Relaxed);
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if let Some(count) = FREES.get(layout.size()) {
+                count.fetch_add(1, Relaxed);
+            }
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    pub fn allocs(size: usize) -> usize {
+        ALLOCS[size].load(Relaxed)
+    }
+
+    pub fn frees(size: usize) -> usize {
+        FREES[size].load(Relaxed)
+    }
+}
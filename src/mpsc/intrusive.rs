@@ -0,0 +1,444 @@
+//! An intrusive variant of [`super::MpscQueue`], linking elements the caller owns.
+//!
+//! Elements embed a [`Link`] and say where through [`Linked`]. Pushing one
+//! hands the queue a pointer to it and allocates nothing, which suits actor
+//! mailboxes whose messages already sit in an allocation of their own. The
+//! queue keeps a stub link so it never runs out of nodes, and the consumer puts
+//! it back whenever it is about to take the last element (Vyukov's intrusive
+//! MPSC queue).
+
+use alloc::sync::Arc;
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::cache_padded::CachePadded;
+use crate::counter::Counter;
+use crate::sync::atomic::AtomicPtr;
+use crate::sync::UnsafeCell;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::waiter::Waiters;
+
+/// The part of an element the queue links it through.
+pub struct Link {
+    next: AtomicPtr<Link>,
+}
+
+/// An element that can be pushed on an intrusive [`MpscQueue`].
+///
+/// # Safety
+///
+/// [`Linked::link`] must always return the same link for the same element, one
+/// embedded in it and used for nothing else, and [`Linked::from_link`] must
+/// turn that link back into the element.
+pub unsafe trait Linked {
+    /// The link embedded in `this`.
+    fn link(this: NonNull<Self>) -> NonNull<Link>;
+
+    /// The element `link` is embedded in.
+    ///
+    /// # Safety
+    ///
+    /// `link` must have come from [`Linked::link`].
+    unsafe fn from_link(link: NonNull<Link>) -> NonNull<Self>;
+}
+
+/// An unbounded multi-producer single-consumer queue of elements it doesn't own.
+///
+/// Elements still queued when the queue is dropped are forgotten, pop them
+/// first if they own anything.
+pub struct MpscQueue<T> {
+    // most recently pushed link, swapped in by producers
+    head: CachePadded<AtomicPtr<Link>>,
+    // the oldest link, or the stub while it is in line. only ever touched by the consumer
+    tail: CachePadded<UnsafeCell<*mut Link>>,
+    // only gets linked in once the queue sits in its arc and won't move anymore
+    stub: Link,
+    waiters: Waiters,
+    len: Counter,
+    _marker: PhantomData<NonNull<T>>,
+}
+
+/// The pushing half of an intrusive [`MpscQueue`], clone it to get more producers.
+pub struct Producer<T> {
+    queue: Arc<MpscQueue<T>>,
+}
+
+/// The popping half of an intrusive [`MpscQueue`].
+pub struct Consumer<T> {
+    queue: Arc<MpscQueue<T>>,
+}
+
+unsafe impl<T: Send> Send for MpscQueue<T> {}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+
+unsafe impl<T: Send> Sync for Producer<T> {}
+
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+impl Link {
+    pub fn new() -> Link {
+        Link {
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+impl Default for Link {
+    fn default() -> Link {
+        Link::new()
+    }
+}
+
+impl<T: Linked> MpscQueue<T> {
+    pub fn new() -> MpscQueue<T> {
+        MpscQueue {
+            head: CachePadded::new(AtomicPtr::new(ptr::null_mut())),
+            tail: CachePadded::new(UnsafeCell::new(ptr::null_mut())),
+            stub: Link::new(),
+            waiters: Waiters::new(),
+            len: Counter::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn split(self) -> (Producer<T>, Consumer<T>) {
+        let mut queue = Arc::new(self);
+
+        let inner = Arc::get_mut(&mut queue).unwrap();
+        let stub = inner.stub();
+        inner.head.store(stub, Relaxed);
+        inner.tail.with_mut(|tail| unsafe { *tail = stub });
+
+        let producer = Producer {
+            queue: queue.clone(),
+        };
+        let consumer = Consumer { queue };
+
+        (producer, consumer)
+    }
+
+    fn stub(&self) -> *mut Link {
+        &self.stub as *const Link as *mut Link
+    }
+
+    // links `link` in at the front. until the last store lands the consumer
+    // sees the queue end right before it
+    unsafe fn push_link(&self, link: *mut Link) {
+        (*link).next.store(ptr::null_mut(), Relaxed);
+        let prev = self.head.swap(link, AcqRel);
+        (*prev).next.store(link, Release);
+    }
+}
+
+impl<T: Linked> Default for MpscQueue<T> {
+    fn default() -> MpscQueue<T> {
+        MpscQueue::new()
+    }
+}
+
+impl<T: Linked> Producer<T> {
+    /// Pushes the element `t` points to, which the queue holds until it is popped.
+    ///
+    /// # Safety
+    ///
+    /// `t` must stay valid and its link untouched until [`Consumer::pop`]
+    /// returns it, and it must not be pushed again before then.
+    pub unsafe fn push(&self, t: NonNull<T>) {
+        self.queue.push_link(T::link(t).as_ptr());
+
+        self.queue.len.add(1);
+        self.queue.waiters.notify();
+    }
+
+    /// Roughly how many elements the queue holds, see [`Consumer::len`].
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.queue.len.get()
+    }
+
+    #[cfg(feature = "counted")]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Producer<T> {
+    fn clone(&self) -> Producer<T> {
+        Producer {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T: Linked> Consumer<T> {
+    /// Pops the oldest element, handing it back to the caller.
+    ///
+    /// Returns `None` if the queue is empty, or if the next producer in line
+    /// has swapped itself in but not linked its element yet.
+    pub fn pop(&mut self) -> Option<NonNull<T>> {
+        let queue = &*self.queue;
+        let stub = queue.stub();
+
+        unsafe {
+            let mut tail = queue.tail.with(|tail| *tail);
+            let mut next = (*tail).next.load(Acquire);
+
+            // the stub holds no element, step over it
+            if tail == stub {
+                if next.is_null() {
+                    return None;
+                }
+                queue.tail.with_mut(|t| *t = next);
+                tail = next;
+                next = (*tail).next.load(Acquire);
+            }
+
+            if next.is_null() {
+                // `tail` is the last link we can see, and taking it would leave no link
+                // behind for producers to hang onto. unless a producer is midway
+                // through a push, put the stub back after it first
+                if tail != queue.head.load(Acquire) {
+                    return None;
+                }
+                queue.push_link(stub);
+                next = (*tail).next.load(Acquire);
+                if next.is_null() {
+                    // a producer swapped in between our check and the stub
+                    return None;
+                }
+            }
+
+            queue.tail.with_mut(|t| *t = next);
+            queue.len.sub(1);
+            Some(T::from_link(NonNull::new_unchecked(tail)))
+        }
+    }
+
+    /// Whether the queue is empty, which may no longer hold by the time this returns.
+    pub fn is_empty(&self) -> bool {
+        let queue = &*self.queue;
+        unsafe {
+            let tail = queue.tail.with(|tail| *tail);
+            tail == queue.stub() && (*tail).next.load(Relaxed).is_null()
+        }
+    }
+
+    /// Roughly how many elements the queue holds.
+    ///
+    /// Pushes racing with this may or may not be counted, but the count
+    /// settles on the exact length once they are done.
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.queue.len.get()
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the queue is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&mut self) -> NonNull<T> {
+        let queue = self.queue.clone();
+        queue.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`Consumer::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Option<NonNull<T>> {
+        let queue = self.queue.clone();
+        queue.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`Consumer::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&mut self, deadline: Instant) -> Option<NonNull<T>> {
+        let queue = self.queue.clone();
+        queue.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the queue is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&mut self) -> impl Future<Output = NonNull<T>> + '_ {
+        let queue = self.queue.clone();
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| queue.waiters.poll_pop(cx, &mut registration, || self.pop()))
+    }
+}
+
+#[cfg(test)]
+#[repr(C)]
+struct Message {
+    // first, so a message and its link share an address
+    link: Link,
+    value: usize,
+}
+
+#[cfg(test)]
+unsafe impl Linked for Message {
+    fn link(this: NonNull<Message>) -> NonNull<Link> {
+        this.cast()
+    }
+
+    unsafe fn from_link(link: NonNull<Link>) -> NonNull<Message> {
+        link.cast()
+    }
+}
+
+#[cfg(test)]
+fn message(value: usize) -> NonNull<Message> {
+    let message = std::boxed::Box::new(Message {
+        link: Link::new(),
+        value,
+    });
+    NonNull::from(std::boxed::Box::leak(message))
+}
+
+#[cfg(test)]
+fn take(message: NonNull<Message>) -> usize {
+    unsafe { std::boxed::Box::from_raw(message.as_ptr()).value }
+}
+
+#[test]
+fn push_items() {
+    let (producer, mut consumer) = MpscQueue::new().split();
+    assert!(consumer.is_empty());
+
+    unsafe {
+        producer.push(message(10));
+        producer.push(message(5));
+        producer.push(message(1));
+    }
+    assert!(!consumer.is_empty());
+
+    assert_eq!(consumer.pop().map(take), Some(10));
+    assert_eq!(consumer.pop().map(take), Some(5));
+    assert_eq!(consumer.pop().map(take), Some(1));
+    assert!(consumer.pop().is_none());
+    assert!(consumer.is_empty());
+}
+
+#[test]
+fn reuses_stub() {
+    let (producer, mut consumer) = MpscQueue::new().split();
+
+    // popping the last element each round puts the stub back in line
+    for i in 0..10 {
+        unsafe { producer.push(message(i)) };
+        assert_eq!(consumer.pop().map(take), Some(i));
+        assert!(consumer.pop().is_none());
+    }
+
+    // and an element can go round again once popped
+    let m = message(7);
+    for _ in 0..3 {
+        unsafe { producer.push(m) };
+        assert_eq!(consumer.pop(), Some(m));
+    }
+    take(m);
+}
+
+#[test]
+fn len_around_the_stub() {
+    let (producer, mut consumer) = MpscQueue::new().split();
+    assert!(consumer.is_empty());
+
+    unsafe {
+        producer.push(message(1));
+        producer.push(message(2));
+    }
+    consumer.pop().map(take);
+
+    // the tail is now an element rather than the stub
+    assert!(!consumer.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!((producer.len(), consumer.len()), (1, 1));
+
+    // popping the last element puts the stub back in line, which doesn't count as a push
+    consumer.pop().map(take);
+    assert!(consumer.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(producer.len(), 0);
+
+    // and with the stub in front of it, a new element still counts
+    unsafe { producer.push(message(3)) };
+    assert!(!consumer.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(consumer.len(), 1);
+    consumer.pop().map(take);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn pop_timeout() {
+    use std::thread;
+
+    let (producer, mut consumer) = MpscQueue::new().split();
+    assert!(consumer.pop_timeout(Duration::from_millis(10)).is_none());
+
+    let handle = thread::spawn(move || consumer.pop_timeout(Duration::from_secs(60)).map(take));
+
+    thread::sleep(Duration::from_millis(10));
+    unsafe { producer.push(message(1)) };
+
+    assert_eq!(handle.join().unwrap(), Some(1));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn pop_async() {
+    use crate::waiter::block_on;
+    use std::thread;
+
+    let (producer, mut consumer) = MpscQueue::new().split();
+
+    let handle = thread::spawn(move || {
+        (0..100)
+            .map(|_| take(block_on(consumer.pop_async())))
+            .sum::<usize>()
+    });
+    for i in 0..100 {
+        unsafe { producer.push(message(i)) };
+    }
+
+    assert_eq!(handle.join().unwrap(), 99 * 100 / 2);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const PRODUCERS: usize = 4;
+
+    let (producer, mut consumer) = MpscQueue::new().split();
+
+    let producers: Vec<_> = (0..PRODUCERS)
+        .map(|p| {
+            let our_copy = producer.clone();
+            thread::spawn(move || {
+                for i in 0..RUNS {
+                    unsafe { our_copy.push(message(p * RUNS + i)) };
+                }
+            })
+        })
+        .collect();
+
+    // each producer's items must come out in the order it pushed them
+    let mut last = [None; PRODUCERS];
+    for _ in 0..RUNS * PRODUCERS {
+        let v = take(consumer.pop_blocking());
+        let (p, i) = (v / RUNS, v % RUNS);
+        assert!(last[p].is_none_or(|prev| prev < i));
+        last[p] = Some(i);
+    }
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    assert!(consumer.pop().is_none());
+}
@@ -0,0 +1,362 @@
+//! An unbounded multi-producer single-consumer queue.
+//!
+//! Producers push with a single atomic swap and the consumer pops without any
+//! compare-and-swap (Vyukov's MPSC queue). [`MpscQueue::split`] hands out a
+//! cloneable [`Producer`] and a single [`Consumer`], so only one thread can
+//! ever pop, which is what lets the consumer free nodes without a collector.
+//!
+//! The [`intrusive`] variant links elements the caller allocated instead, with
+//! no allocation per push.
+
+use alloc::sync::Arc;
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use allocator_api2::alloc::{Allocator, Global};
+
+use crate::cache_padded::CachePadded;
+use crate::counter::Counter;
+use crate::raw;
+use crate::sync::atomic::AtomicPtr;
+use crate::sync::UnsafeCell;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::waiter::Waiters;
+
+pub mod intrusive;
+
+pub struct MpscQueue<T, A: Allocator = Global> {
+    // most recently pushed node, swapped in by producers
+    head: CachePadded<AtomicPtr<Node<T>>>,
+    // sentinel node, the oldest element lives in the node right after it.
+    // only ever touched by the consumer
+    tail: CachePadded<UnsafeCell<*mut Node<T>>>,
+    alloc: A,
+    waiters: Waiters,
+    len: Counter,
+}
+
+/// The pushing half of an [`MpscQueue`], clone it to get more producers.
+pub struct Producer<T, A: Allocator = Global> {
+    queue: Arc<MpscQueue<T, A>>,
+}
+
+/// The popping half of an [`MpscQueue`].
+pub struct Consumer<T, A: Allocator = Global> {
+    queue: Arc<MpscQueue<T, A>>,
+}
+
+unsafe impl<T: Send, A: Allocator + Send> Send for MpscQueue<T, A> {}
+
+unsafe impl<T: Send, A: Allocator + Send + Sync> Send for Producer<T, A> {}
+
+unsafe impl<T: Send, A: Allocator + Send + Sync> Sync for Producer<T, A> {}
+
+unsafe impl<T: Send, A: Allocator + Send + Sync> Send for Consumer<T, A> {}
+
+struct Node<T> {
+    // uninitialised for the sentinel, and moved out once a node becomes one
+    data: UnsafeCell<MaybeUninit<T>>,
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn alloc<A: Allocator>(data: MaybeUninit<T>, alloc: &A) -> *mut Node<T> {
+        let node = Node {
+            data: UnsafeCell::new(data),
+            next: AtomicPtr::new(ptr::null_mut()),
+        };
+
+        raw::alloc_in(node, alloc)
+    }
+}
+
+impl<T> MpscQueue<T> {
+    pub fn new() -> MpscQueue<T> {
+        MpscQueue::new_in(Global)
+    }
+}
+
+impl<T, A: Allocator> MpscQueue<T, A> {
+    /// Creates a queue that allocates its nodes from `alloc`.
+    pub fn new_in(alloc: A) -> MpscQueue<T, A> {
+        let sentinel = Node::alloc(MaybeUninit::uninit(), &alloc);
+
+        MpscQueue {
+            head: CachePadded::new(AtomicPtr::new(sentinel)),
+            tail: CachePadded::new(UnsafeCell::new(sentinel)),
+            alloc,
+            waiters: Waiters::new(),
+            len: Counter::new(),
+        }
+    }
+
+    pub fn split(self) -> (Producer<T, A>, Consumer<T, A>) {
+        let queue = Arc::new(self);
+
+        let producer = Producer {
+            queue: queue.clone(),
+        };
+        let consumer = Consumer { queue };
+
+        (producer, consumer)
+    }
+}
+
+impl<T> Default for MpscQueue<T> {
+    fn default() -> MpscQueue<T> {
+        MpscQueue::new()
+    }
+}
+
+impl<T, A: Allocator> Drop for MpscQueue<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            // the sentinel holds no data, every node after it does
+            let sentinel = self.tail.with(|tail| *tail);
+            let mut node = (*sentinel).next.load(Relaxed);
+            raw::dealloc_in(sentinel, &self.alloc);
+
+            while !node.is_null() {
+                let next = (*node).next.load(Relaxed);
+                (*node)
+                    .data
+                    .with_mut(|data| (*data).as_mut_ptr().drop_in_place());
+                raw::dealloc_in(node, &self.alloc);
+                node = next;
+            }
+        }
+    }
+}
+
+impl<T, A: Allocator> Producer<T, A> {
+    pub fn push(&self, t: T) {
+        let n = Node::alloc(MaybeUninit::new(t), &self.queue.alloc);
+
+        // claim the spot at the front, then link the previous node to us.
+        // until that store lands the consumer sees the queue end at `prev`
+        let prev = self.queue.head.swap(n, AcqRel);
+        unsafe { (*prev).next.store(n, Release) };
+
+        self.queue.len.add(1);
+        self.queue.waiters.notify();
+    }
+
+    /// Roughly how many elements the queue holds, see [`Consumer::len`].
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.queue.len.get()
+    }
+
+    #[cfg(feature = "counted")]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, A: Allocator> Clone for Producer<T, A> {
+    fn clone(&self) -> Producer<T, A> {
+        Producer {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T, A: Allocator> Consumer<T, A> {
+    /// Pops the oldest element.
+    ///
+    /// Returns `None` if the queue is empty, or if the next producer in line
+    /// has swapped itself in but not linked its node yet.
+    pub fn pop(&mut self) -> Option<T> {
+        unsafe {
+            let tail = self.queue.tail.with(|tail| *tail);
+            let next = (*tail).next.load(Acquire);
+            if next.is_null() {
+                return None;
+            }
+
+            // `next` becomes the new sentinel, no producer can reach the old one anymore
+            self.queue.tail.with_mut(|tail| *tail = next);
+            raw::dealloc_in(tail, &self.queue.alloc);
+            self.queue.len.sub(1);
+
+            Some((*next).data.with(|data| (*data).as_ptr().read()))
+        }
+    }
+
+    /// Whether [`Consumer::pop`] would come up empty right now.
+    pub fn is_empty(&self) -> bool {
+        let tail = self.queue.tail.with(|tail| unsafe { *tail });
+        unsafe { (*tail).next.load(Relaxed).is_null() }
+    }
+
+    /// Roughly how many elements the queue holds.
+    ///
+    /// Pushes racing with this may or may not be counted, but the count
+    /// settles on the exact length once they are done.
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.queue.len.get()
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the queue is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&mut self) -> T {
+        let queue = self.queue.clone();
+        queue.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`Consumer::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Option<T> {
+        let queue = self.queue.clone();
+        queue.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`Consumer::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&mut self, deadline: Instant) -> Option<T> {
+        let queue = self.queue.clone();
+        queue.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the queue is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&mut self) -> impl Future<Output = T> + '_ {
+        let queue = self.queue.clone();
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| queue.waiters.poll_pop(cx, &mut registration, || self.pop()))
+    }
+}
+
+#[test]
+fn push_items() {
+    let (producer, mut consumer) = MpscQueue::new().split();
+
+    producer.push(10);
+    producer.push(5);
+    producer.push(1);
+
+    assert_eq!(consumer.pop().unwrap(), 10);
+    assert_eq!(consumer.pop().unwrap(), 5);
+    assert_eq!(consumer.pop().unwrap(), 1);
+    assert!(consumer.pop().is_none());
+}
+
+#[test]
+fn drop_remaining() {
+    let item = Arc::new(());
+
+    let (producer, mut consumer) = MpscQueue::new().split();
+    for _ in 0..10 {
+        producer.push(item.clone());
+    }
+    drop(consumer.pop());
+    drop(consumer);
+    drop(producer);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn len_settles_after_racing() {
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const PRODUCERS: usize = 4;
+
+    let (producer, mut consumer) = MpscQueue::new().split();
+
+    let handles: Vec<_> = (0..PRODUCERS)
+        .map(|_| {
+            let producer = producer.clone();
+            thread::spawn(move || {
+                for i in 0..RUNS {
+                    producer.push(i);
+                }
+            })
+        })
+        .collect();
+
+    let mut popped = 0;
+    while popped < RUNS {
+        if consumer.pop().is_some() {
+            popped += 1;
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // the pops above raced the pushes, but once they are all done the count is exact
+    assert!(!consumer.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(
+        (producer.len(), consumer.len()),
+        (PRODUCERS * RUNS - RUNS, PRODUCERS * RUNS - RUNS)
+    );
+
+    while consumer.pop().is_some() {}
+    assert!(consumer.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(producer.len(), 0);
+}
+
+#[test]
+fn custom_allocator() {
+    use crate::raw::CountingAlloc;
+
+    let alloc = CountingAlloc::default();
+    let (producer, mut consumer) = MpscQueue::new_in(alloc.clone()).split();
+    for i in 0..10 {
+        producer.push(i);
+    }
+    assert_eq!(consumer.pop(), Some(0));
+
+    // the sentinel plus one node per push
+    assert_eq!(alloc.allocs.load(Relaxed), 11);
+    drop(producer);
+    drop(consumer);
+    assert_eq!(alloc.live.load(Relaxed), 0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const PRODUCERS: usize = 4;
+
+    let (producer, mut consumer) = MpscQueue::new().split();
+
+    let producers: Vec<_> = (0..PRODUCERS)
+        .map(|p| {
+            let our_copy = producer.clone();
+            thread::spawn(move || {
+                for i in 0..RUNS {
+                    our_copy.push((p, i));
+                }
+            })
+        })
+        .collect();
+
+    // each producer's items must come out in the order it pushed them
+    let mut last = [None; PRODUCERS];
+    for _ in 0..RUNS * PRODUCERS {
+        let (p, i) = consumer.pop_blocking();
+        assert!(last[p].is_none_or(|prev| prev < i));
+        last[p] = Some(i);
+    }
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    assert!(consumer.pop().is_none());
+}
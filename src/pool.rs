@@ -0,0 +1,374 @@
+//! A cache of node allocations shared by every container allocating from it.
+//!
+//! [`Pool`] is an allocator that sits in front of the global one. Nodes freed
+//! through it go into a small magazine owned by the freeing thread, one per
+//! size class. A full magazine spills into a shared lock-free stack of
+//! magazines, and an empty one refills with the magazine on top of it, so
+//! pushes on one thread can reuse nodes popped on another without a trip to
+//! the allocator.
+//!
+//! Each shared stack holds at most [`max_cached_nodes`] nodes, anything spilled
+//! beyond that goes straight back to the allocator. On top of that every
+//! thread keeps up to a magazine's worth of nodes per size class.
+
+use core::alloc::Layout;
+use core::cell::RefCell;
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+use allocator_api2::alloc::{AllocError, Allocator, Global};
+
+// size classes are multiples of this, which is also the alignment of every cached block
+const GRANULE: usize = 16;
+// nodes above `CLASSES * GRANULE` bytes bypass the cache
+const CLASSES: usize = 16;
+// a magazine spills into the shared list once it holds this many blocks
+const MAGAZINE_CAP: usize = 32;
+
+const DEFAULT_MAX_CACHED: usize = 4096;
+
+static MAX_CACHED: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_CACHED);
+
+// spilled magazines, each a chain of blocks linked through `next`, stacked through
+// the `chain` of their first block
+static SHARED: [AtomicPtr<Block>; CLASSES] = [const { AtomicPtr::new(ptr::null_mut()) }; CLASSES];
+// set while a thread takes a magazine off the stack. with a single taker, the
+// magazine on top can't be taken and pushed back while we read its `chain` (ABA)
+static TAKING: [AtomicBool; CLASSES] = [const { AtomicBool::new(false) }; CLASSES];
+// how many blocks each shared stack holds, counted before they are pushed and after they are taken
+static SHARED_LEN: [AtomicUsize; CLASSES] = [const { AtomicUsize::new(0) }; CLASSES];
+
+/// An allocator that caches small freed blocks for reuse.
+///
+/// Containers only allocate from it when given it through their `new_in`,
+/// as in `Stack::new_in(Pool)`.
+///
+/// Anything above 256 bytes, or aligned to more than 16, goes straight to [`Global`].
+///
+/// `Pool` is a unit type, so every container allocating from it, in this
+/// crate or any other, shares the same cache and the same
+/// [`max_cached_nodes`] limit per size class.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pool;
+
+struct Block {
+    // the next block in its magazine
+    next: *mut Block,
+    // on the first block of a magazine, the first block of the one below it
+    chain: *mut Block,
+}
+
+// every cached block has to fit one
+const _: () = assert!(mem::size_of::<Block>() <= GRANULE);
+
+struct Magazines([Vec<*mut Block>; CLASSES]);
+
+thread_local! {
+    static MAGAZINES: RefCell<Magazines> = const { RefCell::new(Magazines([const { Vec::new() }; CLASSES])) };
+}
+
+/// Caps how many freed nodes of each size class are shared between threads.
+///
+/// The limit is global to the process: it applies separately to each size
+/// class, across every container that allocates from [`Pool`].
+///
+/// Nodes already cached above a lowered limit are handed out before any new
+/// ones are cached. Zero leaves only the per-thread magazines.
+pub fn set_max_cached_nodes(max: usize) {
+    MAX_CACHED.store(max, Relaxed);
+}
+
+/// The current cap on shared nodes per size class, 4096 unless changed by [`set_max_cached_nodes`].
+pub fn max_cached_nodes() -> usize {
+    MAX_CACHED.load(Relaxed)
+}
+
+/// How many freed nodes are currently shared between threads, not counting the magazines.
+pub fn cached_nodes() -> usize {
+    SHARED_LEN.iter().map(|len| len.load(Relaxed)).sum()
+}
+
+unsafe impl Allocator for Pool {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let class = match class(layout) {
+            Some(class) => class,
+            None => return Global.allocate(layout),
+        };
+
+        let size = class_layout(class).size();
+        match take(class) {
+            Some(block) => {
+                let block = unsafe { NonNull::new_unchecked(block as *mut u8) };
+                Ok(NonNull::slice_from_raw_parts(block, size))
+            }
+            None => Global.allocate(class_layout(class)),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match class(layout) {
+            Some(class) => give(class, ptr.as_ptr() as *mut Block),
+            None => Global.deallocate(ptr, layout),
+        }
+    }
+}
+
+fn class(layout: Layout) -> Option<usize> {
+    if layout.align() > GRANULE || layout.size() > CLASSES * GRANULE {
+        return None;
+    }
+
+    Some(layout.size().max(1).div_ceil(GRANULE) - 1)
+}
+
+fn class_layout(class: usize) -> Layout {
+    Layout::from_size_align((class + 1) * GRANULE, GRANULE).unwrap()
+}
+
+fn take(class: usize) -> Option<*mut Block> {
+    // the magazines are gone if the thread is exiting, skip the cache then
+    MAGAZINES
+        .try_with(|magazines| {
+            let magazine = &mut magazines.borrow_mut().0[class];
+            if magazine.is_empty() {
+                refill(class, magazine);
+            }
+            magazine.pop()
+        })
+        .ok()
+        .flatten()
+}
+
+unsafe fn give(class: usize, block: *mut Block) {
+    let kept = MAGAZINES.try_with(|magazines| {
+        let magazine = &mut magazines.borrow_mut().0[class];
+        if magazine.len() >= MAGAZINE_CAP {
+            spill(class, magazine);
+        }
+        magazine.push(block);
+    });
+
+    if kept.is_err() {
+        spill(class, &mut vec![block]);
+    }
+}
+
+// takes the magazine on top of the shared stack for `class` into `magazine`
+fn refill(class: usize, magazine: &mut Vec<*mut Block>) {
+    if SHARED[class].load(Relaxed).is_null() {
+        return;
+    }
+
+    // another thread is taking one already, ours can come from the allocator this time
+    if TAKING[class].swap(true, Acquire) {
+        return;
+    }
+
+    let mut first = SHARED[class].load(Acquire);
+    while !first.is_null() {
+        // only we take magazines off, so `first` stays on the stack until our CAS
+        let below = unsafe { (*first).chain };
+        match SHARED[class].compare_exchange_weak(first, below, Acquire, Acquire) {
+            Ok(_) => break,
+            Err(current) => first = current,
+        }
+    }
+
+    TAKING[class].store(false, Release);
+
+    let mut block = first;
+    let mut taken = 0;
+    while !block.is_null() {
+        magazine.push(block);
+        block = unsafe { (*block).next };
+        taken += 1;
+    }
+    SHARED_LEN[class].fetch_sub(taken, Relaxed);
+}
+
+// pushes every block in `magazine` onto the shared stack for `class`, or frees
+// the ones that would take it over the cap
+fn spill(class: usize, magazine: &mut Vec<*mut Block>) {
+    spill_within(class, magazine, MAX_CACHED.load(Relaxed));
+}
+
+fn spill_within(class: usize, magazine: &mut Vec<*mut Block>, max: usize) {
+    if magazine.is_empty() {
+        return;
+    }
+
+    // reserve room before pushing, so spills racing each other can't overshoot the cap together
+    let len = &SHARED_LEN[class];
+    let cached = len.fetch_add(magazine.len(), Relaxed);
+    let room = max.saturating_sub(cached).min(magazine.len());
+    len.fetch_sub(magazine.len() - room, Relaxed);
+
+    for block in magazine.drain(room..) {
+        unsafe {
+            Global.deallocate(
+                NonNull::new_unchecked(block as *mut u8),
+                class_layout(class),
+            );
+        }
+    }
+
+    if let Some(&first) = magazine.first() {
+        unsafe {
+            for pair in magazine.windows(2) {
+                (*pair[0]).next = pair[1];
+            }
+            (**magazine.last().unwrap()).next = ptr::null_mut();
+            push_magazine(class, first);
+        }
+    }
+
+    magazine.clear();
+}
+
+// pushes the magazine starting at `first` onto the shared stack for `class`
+unsafe fn push_magazine(class: usize, first: *mut Block) {
+    let mut top = SHARED[class].load(Relaxed);
+    loop {
+        (*first).chain = top;
+
+        match SHARED[class].compare_exchange_weak(top, first, Release, Relaxed) {
+            Ok(_) => return,
+            Err(current) => top = current,
+        }
+    }
+}
+
+impl Drop for Magazines {
+    fn drop(&mut self) {
+        // the thread is exiting, leave its blocks to the others
+        for (class, magazine) in self.0.iter_mut().enumerate() {
+            spill(class, magazine);
+        }
+    }
+}
+
+#[test]
+fn reuses_freed_blocks() {
+    let layout = Layout::new::<[u64; 4]>();
+
+    let first = Pool.allocate(layout).unwrap();
+    unsafe { Pool.deallocate(first.cast(), layout) };
+
+    // nothing else runs on this thread, so the block is still in our magazine
+    let second = Pool.allocate(layout).unwrap();
+    assert_eq!(first.cast::<u8>(), second.cast::<u8>());
+    unsafe { Pool.deallocate(second.cast(), layout) };
+}
+
+#[test]
+fn stack_in_pool() {
+    use crate::reclaim::test_reclaimer;
+    use crate::Stack;
+
+    let stack = Stack::with_reclaimer_in(test_reclaimer(), Pool);
+    stack.push(1);
+    stack.push(2);
+    assert_eq!(stack.pop(), Some(2));
+    assert_eq!(stack.pop(), Some(1));
+    assert!(stack.pop().is_none());
+}
+
+#[test]
+fn oversized_bypasses_cache() {
+    let layout = Layout::new::<[u8; CLASSES * GRANULE + 1]>();
+
+    let block = Pool.allocate(layout).unwrap();
+    unsafe { Pool.deallocate(block.cast(), layout) };
+
+    assert_eq!(class(layout), None);
+    assert_eq!(class(Layout::new::<u8>()), Some(0));
+    assert_eq!(class(Layout::new::<[u8; GRANULE + 1]>()), Some(1));
+}
+
+#[test]
+fn refill_takes_one_magazine() {
+    use std::thread;
+
+    // no other test allocates this size class
+    let layout = Layout::new::<[u64; 29]>();
+    let class = class(layout).unwrap();
+
+    // a thread freeing three magazines' worth spills them all onto the shared list
+    thread::spawn(move || {
+        let blocks: Vec<_> = (0..3 * MAGAZINE_CAP)
+            .map(|_| Pool.allocate(layout).unwrap())
+            .collect();
+        for block in blocks {
+            unsafe { Pool.deallocate(block.cast(), layout) };
+        }
+    })
+    .join()
+    .unwrap();
+    assert_eq!(SHARED_LEN[class].load(Relaxed), 3 * MAGAZINE_CAP);
+
+    // an empty magazine refills with one magazine's worth, leaving the rest to other threads
+    let block = Pool.allocate(layout).unwrap();
+    assert_eq!(SHARED_LEN[class].load(Relaxed), 2 * MAGAZINE_CAP);
+    unsafe { Pool.deallocate(block.cast(), layout) };
+}
+
+#[test]
+fn spill_keeps_up_to_the_cap() {
+    // no other test allocates this size class
+    let layout = Layout::new::<[u64; 25]>();
+    let class = class(layout).unwrap();
+
+    let mut magazine: Vec<_> = (0..10)
+        .map(|_| Global.allocate(class_layout(class)).unwrap().as_ptr() as *mut Block)
+        .collect();
+
+    // only the blocks that don't fit are freed
+    spill_within(class, &mut magazine, 4);
+    assert!(magazine.is_empty());
+    assert_eq!(SHARED_LEN[class].load(Relaxed), 4);
+
+    refill(class, &mut magazine);
+    assert_eq!(magazine.len(), 4);
+    assert_eq!(SHARED_LEN[class].load(Relaxed), 0);
+    for block in magazine {
+        unsafe {
+            Global.deallocate(
+                NonNull::new_unchecked(block as *mut u8),
+                class_layout(class),
+            )
+        };
+    }
+}
+
+#[test]
+fn shared_across_threads() {
+    use std::collections::HashSet;
+    use std::thread;
+
+    let layout = Layout::new::<[u64; 31]>();
+
+    // blocks freed by a thread that exits end up on the shared list
+    let freed: HashSet<usize> = thread::spawn(move || {
+        let blocks: Vec<_> = (0..8).map(|_| Pool.allocate(layout).unwrap()).collect();
+        let addrs = blocks
+            .iter()
+            .map(|b| b.cast::<u8>().as_ptr() as usize)
+            .collect();
+        for block in blocks {
+            unsafe { Pool.deallocate(block.cast(), layout) };
+        }
+        addrs
+    })
+    .join()
+    .unwrap();
+
+    let blocks: Vec<_> = (0..8).map(|_| Pool.allocate(layout).unwrap()).collect();
+    assert!(blocks
+        .iter()
+        .any(|b| freed.contains(&(b.cast::<u8>().as_ptr() as usize))));
+    for block in blocks {
+        unsafe { Pool.deallocate(block.cast(), layout) };
+    }
+}
@@ -0,0 +1,493 @@
+#[cfg(feature = "flize")]
+use alloc::sync::Arc;
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use allocator_api2::alloc::{Allocator, Global};
+
+use crate::backoff::{Backoff, Exponential};
+use crate::cache_padded::CachePadded;
+use crate::counter::Counter;
+use crate::raw;
+#[cfg(test)]
+use crate::reclaim::test_reclaimer;
+#[cfg(feature = "flize")]
+use crate::reclaim::{Collector, Flize};
+use crate::reclaim::{DefaultReclaimer, Guard, Reclaimer};
+use crate::sync::atomic::AtomicPtr;
+use crate::sync::UnsafeCell;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::waiter::Waiters;
+
+/// An unbounded multi-producer multi-consumer FIFO queue.
+///
+/// This is the Michael–Scott queue: `head` always points at a sentinel node
+/// and the oldest element lives in the node right after it.
+pub struct Queue<T, R = DefaultReclaimer, A: Allocator = Global, B = Exponential> {
+    head: CachePadded<AtomicPtr<Node<T, A>>>,
+    tail: CachePadded<AtomicPtr<Node<T, A>>>,
+    reclaimer: R,
+    alloc: A,
+    waiters: Waiters,
+    len: Counter,
+    backoff: B,
+}
+
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync, B: Send> Send for Queue<T, R, A, B> {}
+
+unsafe impl<T: Send, R: Reclaimer, A: Allocator + Send + Sync, B: Sync> Sync for Queue<T, R, A, B> {}
+
+struct Node<T, A> {
+    // uninitialised for the sentinel, and moved out once a node becomes one
+    data: UnsafeCell<MaybeUninit<T>>,
+    next: AtomicPtr<Node<T, A>>,
+    // the reclaimer may free the node after the queue is gone, so it carries its own allocator
+    alloc: A,
+}
+
+impl<T, A: Allocator> Node<T, A> {
+    fn alloc(data: MaybeUninit<T>, alloc: &A) -> *mut Node<T, A>
+    where
+        A: Clone,
+    {
+        let node = Node {
+            data: UnsafeCell::new(data),
+            next: AtomicPtr::new(ptr::null_mut()),
+            alloc: alloc.clone(),
+        };
+
+        raw::alloc_in(node, alloc)
+    }
+
+    // handed to `Guard::retire`, a retired node is always an old sentinel holding no data
+    unsafe fn free(ptr: *mut u8) {
+        let node = ptr as *mut Node<T, A>;
+        let alloc = ptr::read(&(*node).alloc);
+        raw::dealloc_in(node, &alloc);
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Queue<T> {
+    pub fn new() -> Queue<T> {
+        Queue::with_reclaimer(DefaultReclaimer::default())
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T, A: Allocator + Clone + 'static> Queue<T, DefaultReclaimer, A> {
+    /// Creates a queue that allocates its nodes from `alloc`.
+    ///
+    /// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+    /// and how to use a short-lived arena anyway.
+    pub fn new_in(alloc: A) -> Queue<T, DefaultReclaimer, A> {
+        Queue::with_reclaimer_in(DefaultReclaimer::default(), alloc)
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Queue<T, Flize> {
+    /// Creates a queue that retires its nodes through `collector`, which may be shared with other containers.
+    pub fn with_collector(collector: &Arc<Collector>) -> Queue<T, Flize> {
+        Queue::with_reclaimer(Flize::with_collector(collector))
+    }
+}
+
+impl<T, R: Reclaimer> Queue<T, R> {
+    /// Creates a queue that frees its nodes through `reclaimer`.
+    pub fn with_reclaimer(reclaimer: R) -> Queue<T, R> {
+        Queue::with_reclaimer_in(reclaimer, Global)
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static> Queue<T, R, A> {
+    /// Creates a queue that allocates its nodes from `alloc` and frees them through `reclaimer`.
+    ///
+    /// `alloc` has to be `'static`, see [allocators](crate#allocators) for why
+    /// and how to use a short-lived arena anyway.
+    pub fn with_reclaimer_in(reclaimer: R, alloc: A) -> Queue<T, R, A> {
+        let sentinel = Node::alloc(MaybeUninit::uninit(), &alloc);
+
+        Queue {
+            head: CachePadded::new(AtomicPtr::new(sentinel)),
+            tail: CachePadded::new(AtomicPtr::new(sentinel)),
+            reclaimer,
+            alloc,
+            waiters: Waiters::new(),
+            len: Counter::new(),
+            backoff: Exponential::default(),
+        }
+    }
+}
+
+impl<T, R: Reclaimer, A: Allocator + Clone + 'static, B: Backoff> Queue<T, R, A, B> {
+    /// Makes the queue pause through `backoff` whenever a CAS on `head` or `tail` loses a race.
+    pub fn with_backoff<B2: Backoff>(self, backoff: B2) -> Queue<T, R, A, B2> {
+        let this = ManuallyDrop::new(self);
+
+        // move everything over, `this` is never dropped so the nodes stay ours
+        unsafe {
+            drop(ptr::read(&this.backoff));
+            Queue {
+                head: ptr::read(&this.head),
+                tail: ptr::read(&this.tail),
+                reclaimer: ptr::read(&this.reclaimer),
+                alloc: ptr::read(&this.alloc),
+                waiters: ptr::read(&this.waiters),
+                len: ptr::read(&this.len),
+                backoff,
+            }
+        }
+    }
+
+    /// Pins the current thread, for use with [`Queue::pop_with`] and [`Queue::push_with`].
+    pub fn pin(&self) -> R::Guard<'_> {
+        self.reclaimer.pin()
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.pop_with(&self.reclaimer.pin())
+    }
+
+    /// Like [`Queue::pop`], but protected by a `guard` the caller already holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guard` was not pinned through this queue's reclaimer, or one sharing its state.
+    pub fn pop_with(&self, guard: &R::Guard<'_>) -> Option<T> {
+        self.check(guard);
+
+        let mut attempt = 0;
+        loop {
+            unsafe {
+                let head = guard.protect(0, &self.head);
+                let next = guard.protect(1, &(*head).next);
+
+                // `next` is only safe to use while `head` is still the sentinel
+                if self.head.load(Acquire) != head {
+                    continue;
+                }
+                if next.is_null() {
+                    return None;
+                }
+
+                // never let `tail` fall behind `head`, otherwise it could point at freed memory
+                let tail = self.tail.load(Acquire);
+                if tail == head {
+                    let _ = self.tail.compare_exchange(tail, next, Release, Relaxed);
+                }
+
+                // if snapshot is still good, `next` becomes the new sentinel
+                if self
+                    .head
+                    .compare_exchange(head, next, Release, Relaxed)
+                    .is_ok()
+                {
+                    self.len.sub(1);
+
+                    // extract out the data, the old sentinel holds none so freeing it is enough
+                    let data = (*next).data.with(|data| (*data).as_ptr().read());
+                    guard.retire(head as *mut u8, Node::<T, A>::free);
+                    return Some(data);
+                }
+            }
+            self.backoff.backoff(attempt);
+            attempt += 1;
+        }
+    }
+
+    pub fn push(&self, t: T) {
+        self.push_with(t, &self.reclaimer.pin())
+    }
+
+    /// Like [`Queue::push`], but protected by a `guard` the caller already holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guard` was not pinned through this queue's reclaimer, or one sharing its state.
+    pub fn push_with(&self, t: T, guard: &R::Guard<'_>) {
+        self.check(guard);
+
+        let n = Node::alloc(MaybeUninit::new(t), &self.alloc);
+        let mut attempt = 0;
+        loop {
+            unsafe {
+                // snapshot current tail
+                let tail = guard.protect(0, &self.tail);
+                let next = (*tail).next.load(Acquire);
+
+                // someone linked a node but has not swung `tail` yet, help them out and retry
+                if !next.is_null() {
+                    let _ = self.tail.compare_exchange(tail, next, Release, Relaxed);
+                    continue;
+                }
+
+                // if `tail` is still the last node, link in the new one and try to swing `tail`
+                if (*tail)
+                    .next
+                    .compare_exchange(ptr::null_mut(), n, Release, Relaxed)
+                    .is_ok()
+                {
+                    let _ = self.tail.compare_exchange(tail, n, Release, Relaxed);
+                    self.len.add(1);
+                    break;
+                }
+            }
+            self.backoff.backoff(attempt);
+            attempt += 1;
+        }
+
+        self.waiters.notify();
+    }
+
+    /// Whether the queue is empty, which may no longer hold by the time this returns.
+    pub fn is_empty(&self) -> bool {
+        let guard = self.reclaimer.pin();
+        let head = guard.protect(0, &self.head);
+
+        // the sentinel holds no data, so only a node after it counts
+        unsafe { (*head).next.load(Relaxed).is_null() }
+    }
+
+    /// Roughly how many elements the queue holds.
+    ///
+    /// Pushes and pops racing with this may or may not be counted, but the
+    /// count settles on the exact length once they are done.
+    #[cfg(feature = "counted")]
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the queue is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&self) -> T {
+        self.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`Queue::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`Queue::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&self, deadline: Instant) -> Option<T> {
+        self.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the queue is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&self) -> impl Future<Output = T> + '_ {
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| self.waiters.poll_pop(cx, &mut registration, || self.pop()))
+    }
+
+    fn check(&self, guard: &R::Guard<'_>) {
+        assert!(
+            self.reclaimer.owns(guard),
+            "guard belongs to a different reclaimer"
+        );
+    }
+}
+
+impl<T, R, A: Allocator, B> Queue<T, R, A, B> {
+    pub(crate) fn waiters(&self) -> &Waiters {
+        &self.waiters
+    }
+}
+
+#[cfg(feature = "flize")]
+impl<T> Default for Queue<T> {
+    fn default() -> Queue<T> {
+        Queue::new()
+    }
+}
+
+impl<T, R, A: Allocator, B> Drop for Queue<T, R, A, B> {
+    fn drop(&mut self) {
+        unsafe {
+            // the sentinel holds no data, every node after it does
+            let sentinel = self.head.load(Relaxed);
+            let mut node = (*sentinel).next.load(Relaxed);
+            Node::<T, A>::free(sentinel as *mut u8);
+
+            while !node.is_null() {
+                let next = (*node).next.load(Relaxed);
+                (*node)
+                    .data
+                    .with_mut(|data| (*data).as_mut_ptr().drop_in_place());
+                Node::<T, A>::free(node as *mut u8);
+                node = next;
+            }
+        }
+    }
+}
+
+#[test]
+fn push_items() {
+    let queue = Queue::with_reclaimer(test_reclaimer());
+
+    queue.push(10);
+    queue.push(5);
+    queue.push(1);
+
+    assert_eq!(queue.pop().unwrap(), 10);
+    assert_eq!(queue.pop().unwrap(), 5);
+    assert_eq!(queue.pop().unwrap(), 1);
+    assert!(queue.pop().is_none());
+}
+
+#[test]
+fn drop_remaining() {
+    use std::sync::Arc;
+
+    let item = Arc::new(());
+
+    let queue = Queue::with_reclaimer(test_reclaimer());
+    for _ in 0..10 {
+        queue.push(item.clone());
+    }
+    drop(queue.pop());
+    drop(queue);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn len_settles_after_racing() {
+    use std::sync::Arc;
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const THREADS: usize = 4;
+
+    let queue = Arc::new(Queue::with_reclaimer(test_reclaimer()));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let our_copy = queue.clone();
+            thread::spawn(move || {
+                let mut popped = 0;
+                for i in 0..RUNS {
+                    our_copy.push(i);
+                    if i % 2 == 0 && our_copy.pop().is_some() {
+                        popped += 1;
+                    }
+                }
+                popped
+            })
+        })
+        .collect();
+
+    let popped: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    let left = THREADS * RUNS - popped;
+
+    // a pop may count itself before the push it took from, but once everyone is done the count is exact
+    assert!(!queue.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(queue.len(), left);
+
+    for _ in 0..left {
+        queue.pop().unwrap();
+    }
+    assert!(queue.is_empty());
+    #[cfg(feature = "counted")]
+    assert_eq!(queue.len(), 0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn backs_off_on_lost_cas() {
+    use crate::backoff::{self, Counting};
+
+    let backoff = Counting::default();
+    let queue = Queue::with_reclaimer(test_reclaimer()).with_backoff(backoff.clone());
+
+    backoff::collide(&backoff, || {
+        queue.push(1);
+        queue.pop();
+    });
+}
+
+#[cfg(feature = "flize")]
+#[test]
+fn pop_frees_nodes() {
+    use flize::Shield;
+
+    use crate::counting_alloc;
+    use std::mem;
+
+    const RUNS: usize = 1_000;
+    // large enough that nothing but these nodes shares their allocation size
+    let size = mem::size_of::<Node<[u8; 2900], Global>>();
+
+    let queue = Queue::with_reclaimer(crate::reclaim::Flize::new());
+
+    let allocs = counting_alloc::allocs(size);
+    let frees = counting_alloc::frees(size);
+
+    for _ in 0..RUNS {
+        queue.push([0u8; 2900]);
+    }
+    for _ in 0..RUNS {
+        queue.pop().unwrap();
+    }
+    assert_eq!(counting_alloc::allocs(size) - allocs, RUNS);
+
+    // push any partially filled bag to the collector and run it until everything retired is freed
+    let collector = &queue.reclaimer.collector;
+    collector.thin_shield().flush();
+    for _ in 0..10 {
+        if counting_alloc::frees(size) - frees == RUNS {
+            break;
+        }
+        let _ = collector.try_collect_light();
+    }
+
+    // the first sentinel was allocated before counting started and the last one is still live
+    assert_eq!(counting_alloc::frees(size) - frees, RUNS);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::sync::Arc;
+    use std::thread;
+
+    const RUNS: usize = 10_000;
+    const PRODUCERS: usize = 4;
+
+    let queue = Arc::new(Queue::with_reclaimer(test_reclaimer()));
+
+    let producers: Vec<_> = (0..PRODUCERS)
+        .map(|p| {
+            let our_copy = queue.clone();
+            thread::spawn(move || {
+                for i in 0..RUNS {
+                    our_copy.push((p, i));
+                }
+            })
+        })
+        .collect();
+
+    // each producer's items must come out in the order it pushed them
+    let mut last = [None; PRODUCERS];
+    for _ in 0..RUNS * PRODUCERS {
+        let (p, i) = queue.pop_blocking();
+        assert!(last[p].is_none_or(|prev| prev < i));
+        last[p] = Some(i);
+    }
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    assert!(queue.pop().is_none());
+}
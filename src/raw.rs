@@ -0,0 +1,54 @@
+//! Hand-managed allocations through an [`Allocator`], for nodes and buffers
+//! that are linked up with raw pointers instead of owned through a `Box`.
+
+use alloc::alloc::{handle_alloc_error, Layout};
+use core::ptr::NonNull;
+
+use allocator_api2::alloc::Allocator;
+
+/// Moves `value` into memory from `alloc`.
+pub(crate) fn alloc_in<T, A: Allocator>(value: T, alloc: &A) -> *mut T {
+    let layout = Layout::new::<T>();
+    let ptr = match alloc.allocate(layout) {
+        Ok(ptr) => ptr.cast::<T>().as_ptr(),
+        Err(_) => handle_alloc_error(layout),
+    };
+
+    unsafe { ptr.write(value) };
+    ptr
+}
+
+/// Hands the memory behind `ptr` back to `alloc`, without dropping the value.
+///
+/// # Safety
+///
+/// `ptr` must come from [`alloc_in`] with the same allocator, and must not be used afterwards.
+pub(crate) unsafe fn dealloc_in<T, A: Allocator>(ptr: *mut T, alloc: &A) {
+    alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), Layout::new::<T>());
+}
+
+/// Forwards to [`Global`](allocator_api2::alloc::Global), keeping track of how many blocks are live.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct CountingAlloc {
+    pub(crate) allocs: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    pub(crate) live: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+unsafe impl Allocator for CountingAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        self.allocs.fetch_add(1, Relaxed);
+        self.live.fetch_add(1, Relaxed);
+        allocator_api2::alloc::Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        self.live.fetch_sub(1, Relaxed);
+        allocator_api2::alloc::Global.deallocate(ptr, layout)
+    }
+}
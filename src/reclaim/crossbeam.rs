@@ -0,0 +1,38 @@
+use core::sync::atomic::Ordering::Acquire;
+
+use super::{Guard, Reclaimer};
+use crate::sync::atomic::AtomicPtr;
+
+/// Epoch-based reclamation through `crossbeam-epoch`'s global collector.
+#[derive(Default)]
+pub struct CrossbeamEpoch;
+
+impl CrossbeamEpoch {
+    pub fn new() -> CrossbeamEpoch {
+        CrossbeamEpoch
+    }
+}
+
+unsafe impl Reclaimer for CrossbeamEpoch {
+    type Guard<'a> = crossbeam_epoch::Guard;
+
+    fn pin(&self) -> crossbeam_epoch::Guard {
+        crossbeam_epoch::pin()
+    }
+
+    fn owns(&self, _guard: &crossbeam_epoch::Guard) -> bool {
+        // every guard comes from the one global collector
+        true
+    }
+}
+
+unsafe impl Guard for crossbeam_epoch::Guard {
+    fn protect<T>(&self, _slot: usize, src: &AtomicPtr<T>) -> *mut T {
+        // being pinned already keeps everything we can reach alive
+        src.load(Acquire)
+    }
+
+    unsafe fn retire(&self, ptr: *mut u8, free: unsafe fn(*mut u8)) {
+        self.defer_unchecked(move || free(ptr));
+    }
+}
@@ -0,0 +1,68 @@
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering::Acquire;
+
+use flize::{Shield, ThinShield};
+
+pub use flize::Collector;
+
+use super::{Guard, Reclaimer};
+use crate::sync::atomic::AtomicPtr;
+
+/// Epoch-based reclamation through a [`flize::Collector`].
+///
+/// Every container gets a collector of its own by default, but any number of
+/// them can share one through [`Flize::with_collector`], which also lets a
+/// single guard cover operations on all of them.
+pub struct Flize {
+    pub(crate) collector: Arc<Collector>,
+}
+
+pub struct FlizeGuard<'a> {
+    collector: &'a Collector,
+    shield: ThinShield<'a>,
+}
+
+impl Flize {
+    pub fn new() -> Flize {
+        Flize::with_collector(&Arc::new(Collector::new()))
+    }
+
+    /// Retires nodes through `collector`, which may be shared with other containers.
+    pub fn with_collector(collector: &Arc<Collector>) -> Flize {
+        Flize {
+            collector: collector.clone(),
+        }
+    }
+}
+
+impl Default for Flize {
+    fn default() -> Flize {
+        Flize::new()
+    }
+}
+
+unsafe impl Reclaimer for Flize {
+    type Guard<'a> = FlizeGuard<'a>;
+
+    fn pin(&self) -> FlizeGuard<'_> {
+        FlizeGuard {
+            collector: &self.collector,
+            shield: self.collector.thin_shield(),
+        }
+    }
+
+    fn owns(&self, guard: &FlizeGuard<'_>) -> bool {
+        Arc::as_ptr(&self.collector) == guard.collector
+    }
+}
+
+unsafe impl<'a> Guard for FlizeGuard<'a> {
+    fn protect<T>(&self, _slot: usize, src: &AtomicPtr<T>) -> *mut T {
+        // being pinned already keeps everything we can reach alive
+        src.load(Acquire)
+    }
+
+    unsafe fn retire(&self, ptr: *mut u8, free: unsafe fn(*mut u8)) {
+        self.shield.retire(move || free(ptr));
+    }
+}
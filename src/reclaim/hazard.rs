@@ -0,0 +1,264 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+#[cfg(test)]
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+
+use super::{Guard, Reclaimer, HAZARD_SLOTS};
+use crate::sync::atomic::{fence, AtomicBool, AtomicPtr};
+use crate::sync::UnsafeCell;
+
+// a record never scans before it has retired this many nodes
+const SCAN_THRESHOLD: usize = 64;
+
+/// Hazard-pointer reclamation, with a domain owned by the container.
+///
+/// Every pinned thread holds a record of [`HAZARD_SLOTS`] hazard pointers and
+/// its own list of retired nodes. Once that list outgrows the scan threshold,
+/// the thread frees every node no record currently points at. Unlike epoch
+/// schemes a stalled thread only keeps the few nodes it protects alive, so the
+/// garbage stays bounded at roughly the threshold per record.
+pub struct HazardPointers {
+    // records are only ever added, and freed along with the domain
+    records: AtomicPtr<Record>,
+    scan_threshold: usize,
+}
+
+pub struct HazardGuard<'a> {
+    domain: &'a HazardPointers,
+    record: &'a Record,
+    // the retire list is only ever touched by the thread holding the record
+    _marker: PhantomData<*mut ()>,
+}
+
+struct Record {
+    // set while a guard holds this record
+    active: AtomicBool,
+    hazards: [AtomicPtr<u8>; HAZARD_SLOTS],
+    retired: UnsafeCell<Vec<Retired>>,
+    next: *mut Record,
+}
+
+struct Retired {
+    ptr: *mut u8,
+    free: unsafe fn(*mut u8),
+}
+
+// the retire list is guarded by `active`, everything else is atomic
+unsafe impl Send for HazardPointers {}
+
+unsafe impl Sync for HazardPointers {}
+
+impl HazardPointers {
+    pub fn new() -> HazardPointers {
+        HazardPointers::with_scan_threshold(SCAN_THRESHOLD)
+    }
+
+    /// Creates a domain whose threads scan once they have retired `threshold` nodes.
+    ///
+    /// A low threshold keeps less garbage around, but every scan walks all the
+    /// records, so it should stay well above the number of threads.
+    pub fn with_scan_threshold(threshold: usize) -> HazardPointers {
+        HazardPointers {
+            records: AtomicPtr::new(ptr::null_mut()),
+            scan_threshold: threshold,
+        }
+    }
+
+    // takes over an idle record, or adds a new one if every record is in use
+    fn acquire(&self) -> &Record {
+        let mut record = self.records.load(Acquire);
+        while !record.is_null() {
+            let r = unsafe { &*record };
+            if !r.active.load(Relaxed)
+                && r.active
+                    .compare_exchange(false, true, Acquire, Relaxed)
+                    .is_ok()
+            {
+                return r;
+            }
+            record = r.next;
+        }
+
+        let record = Box::into_raw(Box::new(Record {
+            active: AtomicBool::new(true),
+            hazards: core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            retired: UnsafeCell::new(Vec::new()),
+            next: ptr::null_mut(),
+        }));
+
+        let mut head = self.records.load(Relaxed);
+        loop {
+            unsafe { (*record).next = head };
+
+            match self
+                .records
+                .compare_exchange_weak(head, record, Release, Relaxed)
+            {
+                Ok(_) => return unsafe { &*record },
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // frees every node in `retired` that no record points at
+    unsafe fn scan(&self, retired: &mut Vec<Retired>) {
+        // pairs with the fence in `protect`: either we see the hazard here, or
+        // the protecting thread sees the node already unlinked and retries
+        fence(SeqCst);
+
+        let mut hazards = Vec::new();
+        let mut record = self.records.load(Acquire);
+        while !record.is_null() {
+            for hazard in &(*record).hazards {
+                let p = hazard.load(Acquire);
+                if !p.is_null() {
+                    hazards.push(p);
+                }
+            }
+            record = (*record).next;
+        }
+        hazards.sort_unstable();
+
+        retired.retain(|r| {
+            if hazards.binary_search(&r.ptr).is_ok() {
+                return true;
+            }
+            (r.free)(r.ptr);
+            false
+        });
+    }
+}
+
+impl Default for HazardPointers {
+    fn default() -> HazardPointers {
+        HazardPointers::new()
+    }
+}
+
+unsafe impl Reclaimer for HazardPointers {
+    type Guard<'a> = HazardGuard<'a>;
+
+    fn pin(&self) -> HazardGuard<'_> {
+        HazardGuard {
+            domain: self,
+            record: self.acquire(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn owns(&self, guard: &HazardGuard<'_>) -> bool {
+        ptr::eq(self, guard.domain)
+    }
+}
+
+unsafe impl<'a> Guard for HazardGuard<'a> {
+    fn protect<T>(&self, slot: usize, src: &AtomicPtr<T>) -> *mut T {
+        let hazard = &self.record.hazards[slot];
+
+        let mut p = src.load(Relaxed);
+        loop {
+            hazard.store(p as *mut u8, Relaxed);
+            fence(SeqCst);
+
+            // still reachable after publishing the hazard, so no scan can free it from here on
+            let current = src.load(Acquire);
+            if current == p {
+                return p;
+            }
+            p = current;
+        }
+    }
+
+    unsafe fn retire(&self, ptr: *mut u8, free: unsafe fn(*mut u8)) {
+        self.record.retired.with_mut(|retired| {
+            let retired = &mut *retired;
+            retired.push(Retired { ptr, free });
+
+            if retired.len() >= self.domain.scan_threshold {
+                self.domain.scan(retired);
+            }
+        })
+    }
+}
+
+impl<'a> Drop for HazardGuard<'a> {
+    fn drop(&mut self) {
+        for hazard in &self.record.hazards {
+            hazard.store(ptr::null_mut(), Release);
+        }
+
+        // whoever takes the record next inherits its retire list
+        self.record.active.store(false, Release);
+    }
+}
+
+impl Drop for HazardPointers {
+    fn drop(&mut self) {
+        unsafe {
+            // with `&mut self` no guard is alive, so nothing is protected any more
+            let mut record = self.records.load(Relaxed);
+            while !record.is_null() {
+                let owned = Box::from_raw(record);
+                record = owned.next;
+
+                for r in owned.retired.into_inner() {
+                    (r.free)(r.ptr);
+                }
+            }
+        }
+    }
+}
+
+// frees happen on the retiring thread, so every test gets its own count
+#[cfg(test)]
+thread_local! {
+    static FREED: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(test)]
+unsafe fn free_counted(ptr: *mut u8) {
+    drop(Box::from_raw(ptr));
+    FREED.set(FREED.get() + 1);
+}
+
+#[test]
+fn protected_nodes_survive_scans() {
+    let domain = HazardPointers::with_scan_threshold(1);
+    let node = AtomicPtr::new(Box::into_raw(Box::new(0u8)));
+
+    let reader = domain.pin();
+    let protected = reader.protect(0, &node);
+
+    // a second thread would hold its own record, and so does a second guard
+    let writer = domain.pin();
+    let unlinked = node.swap(ptr::null_mut(), Relaxed);
+    let freed = FREED.get();
+    unsafe { writer.retire(unlinked, free_counted) };
+    assert_eq!(FREED.get(), freed);
+    assert_eq!(unsafe { *protected }, 0);
+
+    // once the reader lets go, the next scan frees it
+    drop(reader);
+    let other = Box::into_raw(Box::new(1u8));
+    unsafe { writer.retire(other, free_counted) };
+    assert_eq!(FREED.get(), freed + 2);
+}
+
+#[test]
+fn drop_frees_retired() {
+    let domain = HazardPointers::new();
+
+    let guard = domain.pin();
+    let freed = FREED.get();
+    for i in 0..10u8 {
+        unsafe { guard.retire(Box::into_raw(Box::new(i)), free_counted) };
+    }
+    drop(guard);
+    assert_eq!(FREED.get(), freed);
+
+    drop(domain);
+    assert_eq!(FREED.get(), freed + 10);
+}
@@ -0,0 +1,40 @@
+use core::sync::atomic::Ordering::Acquire;
+
+use super::{Guard, Reclaimer};
+use crate::sync::atomic::AtomicPtr;
+
+/// Never frees anything, which makes it trivially safe and as cheap as it gets.
+///
+/// Only meant for benchmarking the containers without any reclamation overhead,
+/// every retired node is leaked.
+#[derive(Default)]
+pub struct Leak;
+
+#[derive(Default)]
+pub struct LeakGuard;
+
+impl Leak {
+    pub fn new() -> Leak {
+        Leak
+    }
+}
+
+unsafe impl Reclaimer for Leak {
+    type Guard<'a> = LeakGuard;
+
+    fn pin(&self) -> LeakGuard {
+        LeakGuard
+    }
+
+    fn owns(&self, _guard: &LeakGuard) -> bool {
+        true
+    }
+}
+
+unsafe impl Guard for LeakGuard {
+    fn protect<T>(&self, _slot: usize, src: &AtomicPtr<T>) -> *mut T {
+        src.load(Acquire)
+    }
+
+    unsafe fn retire(&self, _ptr: *mut u8, _free: unsafe fn(*mut u8)) {}
+}
@@ -0,0 +1,129 @@
+//! Memory reclamation backends.
+//!
+//! A node unlinked from a container may still be read by threads that loaded a
+//! pointer to it just before. The container's [`Reclaimer`] decides when such a
+//! node can actually be freed. Each backend sits behind its own cargo feature.
+//!
+//! [`DefaultReclaimer`] is `Flize` whenever the `flize` feature is enabled, no
+//! matter which other backends are. Without it containers have no default
+//! reclaimer, and every other backend is picked explicitly through their
+//! `with_reclaimer` constructors.
+
+use crate::sync::atomic::AtomicPtr;
+
+#[cfg(feature = "crossbeam-epoch")]
+mod crossbeam;
+#[cfg(feature = "flize")]
+mod flize;
+#[cfg(feature = "hazard")]
+mod hazard;
+#[cfg(feature = "leak")]
+mod leak;
+
+#[cfg(feature = "crossbeam-epoch")]
+pub use self::crossbeam::CrossbeamEpoch;
+#[cfg(feature = "flize")]
+pub use self::flize::{Collector, Flize, FlizeGuard};
+#[cfg(feature = "hazard")]
+pub use self::hazard::{HazardGuard, HazardPointers};
+#[cfg(feature = "leak")]
+pub use self::leak::{Leak, LeakGuard};
+
+/// The reclaimer containers use unless told otherwise.
+#[cfg(feature = "flize")]
+pub type DefaultReclaimer = Flize;
+
+/// Stands in for the default reclaimer without the `flize` feature.
+///
+/// It has no values, so containers defaulting to it can't be built, and
+/// their `new` and `Default` are missing.
+#[cfg(not(feature = "flize"))]
+pub type DefaultReclaimer = NoDefaultReclaimer;
+
+/// The default reclaimer type parameter without the `flize` feature, see [`DefaultReclaimer`].
+#[cfg(not(feature = "flize"))]
+pub enum NoDefaultReclaimer {}
+
+// what unit tests build their containers on, since only `flize` gives them a default
+#[cfg(all(test, feature = "flize"))]
+pub(crate) type TestReclaimer = Flize;
+
+#[cfg(all(test, not(feature = "flize"), feature = "crossbeam-epoch"))]
+pub(crate) type TestReclaimer = CrossbeamEpoch;
+
+#[cfg(all(
+    test,
+    not(feature = "flize"),
+    not(feature = "crossbeam-epoch"),
+    feature = "hazard"
+))]
+pub(crate) type TestReclaimer = HazardPointers;
+
+#[cfg(all(
+    test,
+    not(feature = "flize"),
+    not(feature = "crossbeam-epoch"),
+    not(feature = "hazard"),
+    feature = "leak"
+))]
+pub(crate) type TestReclaimer = Leak;
+
+#[cfg(test)]
+pub(crate) fn test_reclaimer() -> TestReclaimer {
+    TestReclaimer::default()
+}
+
+#[cfg(not(any(
+    feature = "flize",
+    feature = "crossbeam-epoch",
+    feature = "hazard",
+    feature = "leak"
+)))]
+compile_error!(
+    "enable at least one reclamation backend: `flize`, `crossbeam-epoch`, `hazard` or `leak`"
+);
+
+/// How many pointers a single [`Guard`] can protect at once.
+pub const HAZARD_SLOTS: usize = 2;
+
+/// A scheme for deciding when unlinked nodes can be freed.
+///
+/// # Safety
+///
+/// A pointer returned by [`Guard::protect`] must stay valid for as long as the
+/// guard is held, however it is retired in the meantime.
+pub unsafe trait Reclaimer: Send + Sync {
+    type Guard<'a>: Guard
+    where
+        Self: 'a;
+
+    /// Starts protecting loads on the current thread until the returned guard is dropped.
+    fn pin(&self) -> Self::Guard<'_>;
+
+    /// Whether `guard` was pinned through this reclaimer, or one sharing its state.
+    ///
+    /// Containers check this before trusting a guard they did not pin themselves.
+    fn owns(&self, guard: &Self::Guard<'_>) -> bool;
+}
+
+/// Protects the nodes loaded through it from being freed while it is held.
+///
+/// # Safety
+///
+/// See [`Reclaimer`].
+pub unsafe trait Guard {
+    /// Loads `src`, making sure whatever it points to is not freed while this guard is held.
+    ///
+    /// Schemes that protect individual pointers keep one per `slot`, which must be
+    /// below [`HAZARD_SLOTS`]. Protecting through the same slot again releases
+    /// the pointer protected before.
+    fn protect<T>(&self, slot: usize, src: &AtomicPtr<T>) -> *mut T;
+
+    /// Schedules `free(ptr)` to run once no guard can still be reading `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must already be unlinked, so that no guard pinned from now on can
+    /// reach it, and must not be retired twice.
+    unsafe fn retire(&self, ptr: *mut u8, free: unsafe fn(*mut u8));
+}
@@ -0,0 +1,353 @@
+//! A bounded single-producer single-consumer ring buffer.
+//!
+//! [`RingBuffer::split`] hands out exactly one [`Producer`] and one [`Consumer`].
+//! Neither handle is `Clone`, so only one thread can ever write and only one
+//! can ever read, which lets both sides get away with plain loads and stores.
+//!
+//! With `std` or `async` enabled, every push also pays for a `SeqCst` fence and
+//! a load to see whether the consumer is parked in one of the blocking or async
+//! pops. Without either there is nobody to wake, and a push is the write and a
+//! single release store.
+
+use alloc::sync::Arc;
+#[cfg(feature = "async")]
+use core::future::{self, Future};
+use core::mem::MaybeUninit;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+
+use crate::cache_padded::CachePadded;
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::UnsafeCell;
+#[cfg(feature = "async")]
+use crate::waiter::Registration;
+use crate::waiter::Waiters;
+
+pub struct RingBuffer<T, A: Allocator = Global> {
+    // a power of two long, so positions map to the same slot across their wrap at `usize::MAX`
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>], A>,
+    capacity: usize,
+    // position of the next pop, only written by the consumer
+    head: CachePadded<AtomicUsize>,
+    // position of the next push, only written by the producer
+    tail: CachePadded<AtomicUsize>,
+    waiters: Waiters,
+}
+
+/// The writing half of a [`RingBuffer`].
+pub struct Producer<T, A: Allocator = Global> {
+    ring: Arc<RingBuffer<T, A>>,
+    // last `head` we saw, the consumer only ever moves it forward
+    head: usize,
+    tail: usize,
+}
+
+/// The reading half of a [`RingBuffer`].
+pub struct Consumer<T, A: Allocator = Global> {
+    ring: Arc<RingBuffer<T, A>>,
+    head: usize,
+    // last `tail` we saw, the producer only ever moves it forward
+    tail: usize,
+}
+
+unsafe impl<T: Send, A: Allocator + Send + Sync> Send for Producer<T, A> {}
+
+unsafe impl<T: Send, A: Allocator + Send + Sync> Send for Consumer<T, A> {}
+
+impl<T> RingBuffer<T> {
+    /// Creates a ring buffer that holds at most `capacity` elements.
+    ///
+    /// Room is made for the next power of two, but no more than `capacity` are ever held.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> RingBuffer<T> {
+        RingBuffer::new_in(capacity, Global)
+    }
+}
+
+impl<T, A: Allocator> RingBuffer<T, A> {
+    /// Like [`RingBuffer::new`], but allocates the slots from `alloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new_in(capacity: usize, alloc: A) -> RingBuffer<T, A> {
+        assert!(capacity > 0, "capacity must be non-zero");
+
+        let len = capacity.next_power_of_two();
+        let mut buffer = Vec::with_capacity_in(len, alloc);
+        buffer.extend((0..len).map(|_| UnsafeCell::new(MaybeUninit::uninit())));
+        let buffer = buffer.into_boxed_slice();
+
+        RingBuffer {
+            buffer,
+            capacity,
+            head: CachePadded::new(AtomicUsize::new(0)),
+            tail: CachePadded::new(AtomicUsize::new(0)),
+            waiters: Waiters::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn split(self) -> (Producer<T, A>, Consumer<T, A>) {
+        let ring = Arc::new(self);
+        let head = ring.head.load(Relaxed);
+        let tail = ring.tail.load(Relaxed);
+
+        let producer = Producer {
+            ring: ring.clone(),
+            head,
+            tail,
+        };
+        let consumer = Consumer { ring, head, tail };
+
+        (producer, consumer)
+    }
+
+    fn slot(&self, pos: usize) -> &UnsafeCell<MaybeUninit<T>> {
+        &self.buffer[pos & (self.buffer.len() - 1)]
+    }
+}
+
+impl<T, A: Allocator> Drop for RingBuffer<T, A> {
+    fn drop(&mut self) {
+        let head = self.head.load(Relaxed);
+        let tail = self.tail.load(Relaxed);
+
+        let mut pos = head;
+        while pos != tail {
+            self.slot(pos)
+                .with_mut(|data| unsafe { (*data).as_mut_ptr().drop_in_place() });
+            pos = pos.wrapping_add(1);
+        }
+    }
+}
+
+impl<T, A: Allocator> Producer<T, A> {
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// How many elements the buffer holds, or more if the consumer is popping meanwhile.
+    pub fn len(&self) -> usize {
+        self.tail.wrapping_sub(self.ring.head.load(Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes `t` onto the back of the buffer, handing it back if the buffer is full.
+    pub fn try_push(&mut self, t: T) -> Result<(), T> {
+        if self.tail.wrapping_sub(self.head) == self.ring.capacity() {
+            // looks full, see how far the consumer has got since we last checked
+            self.head = self.ring.head.load(Acquire);
+            if self.tail.wrapping_sub(self.head) == self.ring.capacity() {
+                return Err(t);
+            }
+        }
+
+        self.ring
+            .slot(self.tail)
+            .with_mut(|data| unsafe { (*data).as_mut_ptr().write(t) });
+        self.tail = self.tail.wrapping_add(1);
+        self.ring.tail.store(self.tail, Release);
+        self.ring.waiters.notify();
+
+        Ok(())
+    }
+}
+
+impl<T, A: Allocator> Consumer<T, A> {
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// How many elements the buffer holds, or fewer if the producer is pushing meanwhile.
+    pub fn len(&self) -> usize {
+        self.ring.tail.load(Relaxed).wrapping_sub(self.head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.head == self.tail {
+            // looks empty, see how far the producer has got since we last checked
+            self.tail = self.ring.tail.load(Acquire);
+            if self.head == self.tail {
+                return None;
+            }
+        }
+
+        let data = self
+            .ring
+            .slot(self.head)
+            .with(|data| unsafe { (*data).as_ptr().read() });
+        self.head = self.head.wrapping_add(1);
+        self.ring.head.store(self.head, Release);
+
+        Some(data)
+    }
+
+    /// Pops an element, parking the thread until one is pushed if the buffer is empty.
+    #[cfg(feature = "std")]
+    pub fn pop_blocking(&mut self) -> T {
+        let ring = self.ring.clone();
+        ring.waiters.wait_until(None, || self.pop()).unwrap()
+    }
+
+    /// Like [`Consumer::pop_blocking`], but gives up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Option<T> {
+        let ring = self.ring.clone();
+        ring.waiters.wait_for(timeout, || self.pop())
+    }
+
+    /// Like [`Consumer::pop_blocking`], but gives up at `deadline`.
+    #[cfg(feature = "std")]
+    pub fn pop_deadline(&mut self, deadline: Instant) -> Option<T> {
+        let ring = self.ring.clone();
+        ring.waiters.wait_until(Some(deadline), || self.pop())
+    }
+
+    /// Pops an element, waiting for one to be pushed if the buffer is empty.
+    #[cfg(feature = "async")]
+    pub fn pop_async(&mut self) -> impl Future<Output = T> + '_ {
+        let ring = self.ring.clone();
+        let mut registration = Registration::new();
+        future::poll_fn(move |cx| ring.waiters.poll_pop(cx, &mut registration, || self.pop()))
+    }
+}
+
+#[test]
+fn push_items() {
+    let (mut producer, mut consumer) = RingBuffer::new(3).split();
+
+    producer.try_push(10).unwrap();
+    producer.try_push(5).unwrap();
+    producer.try_push(1).unwrap();
+    assert_eq!(producer.try_push(7), Err(7));
+
+    assert_eq!(consumer.pop().unwrap(), 10);
+    assert_eq!(consumer.pop().unwrap(), 5);
+    producer.try_push(7).unwrap();
+    assert_eq!(consumer.pop().unwrap(), 1);
+    assert_eq!(consumer.pop().unwrap(), 7);
+    assert!(consumer.pop().is_none());
+}
+
+#[test]
+fn wraps_around_usize() {
+    let ring = RingBuffer::new(3);
+    ring.head.store(usize::MAX - 1, Relaxed);
+    ring.tail.store(usize::MAX - 1, Relaxed);
+    let (mut producer, mut consumer) = ring.split();
+
+    for round in 0..4 {
+        for i in 0..3 {
+            producer.try_push(vec![round, i]).unwrap();
+        }
+        assert!(producer.try_push(vec![]).is_err());
+        for i in 0..3 {
+            assert_eq!(consumer.pop(), Some(vec![round, i]));
+        }
+        assert!(consumer.pop().is_none());
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn pop_timeout() {
+    use std::thread;
+
+    let (mut producer, mut consumer) = RingBuffer::new(1).split();
+    assert!(consumer.pop_timeout(Duration::from_millis(10)).is_none());
+
+    let handle = thread::spawn(move || consumer.pop_timeout(Duration::from_secs(60)));
+
+    thread::sleep(Duration::from_millis(10));
+    producer.try_push(1).unwrap();
+
+    assert_eq!(handle.join().unwrap(), Some(1));
+}
+
+#[test]
+fn drop_remaining() {
+    let item = Arc::new(());
+
+    let (mut producer, mut consumer) = RingBuffer::new(8).split();
+    for _ in 0..5 {
+        producer.try_push(item.clone()).unwrap();
+    }
+    drop(consumer.pop());
+    drop(producer);
+    drop(consumer);
+
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[test]
+fn len_at_full_capacity() {
+    // the indices wrap past `usize::MAX` partway through the first fill
+    let ring = RingBuffer::new(3);
+    ring.head.store(usize::MAX - 1, Relaxed);
+    ring.tail.store(usize::MAX - 1, Relaxed);
+    let (mut producer, mut consumer) = ring.split();
+
+    for _ in 0..4 {
+        for i in 0..3 {
+            producer.try_push(i).unwrap();
+            assert_eq!(consumer.len(), i + 1);
+        }
+        assert!(producer.try_push(3).is_err());
+
+        // a full buffer is `capacity` apart, not zero
+        assert_eq!((producer.len(), consumer.len()), (3, 3));
+        assert!(!producer.is_empty() && !consumer.is_empty());
+
+        for i in 0..3 {
+            consumer.pop().unwrap();
+            assert_eq!(producer.len(), 2 - i);
+        }
+        assert!(producer.is_empty() && consumer.is_empty());
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn thread_test() {
+    use std::thread;
+
+    const RUNS: usize = 100_000;
+
+    let (mut producer, mut consumer) = RingBuffer::new(64).split();
+
+    let handle = thread::spawn(move || {
+        for i in 0..RUNS {
+            let mut item = i;
+            while let Err(back) = producer.try_push(item) {
+                item = back;
+                thread::yield_now();
+            }
+        }
+    });
+
+    for i in 0..RUNS {
+        assert_eq!(consumer.pop_blocking(), i);
+    }
+
+    handle.join().unwrap();
+    assert!(consumer.pop().is_none());
+}
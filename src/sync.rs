@@ -0,0 +1,60 @@
+//! The atomics and cells the containers are built from.
+//!
+//! Under `cfg(loom)` these are [loom]'s instead, so the tests in `tests/loom.rs`
+//! can run every container through each interleaving, and each value a load may
+//! return under the C++ memory model.
+//!
+//! [loom]: https://docs.rs/loom
+
+#[cfg(not(loom))]
+pub(crate) mod atomic {
+    #[cfg(feature = "hazard")]
+    pub(crate) use core::sync::atomic::AtomicBool;
+    pub(crate) use core::sync::atomic::{fence, AtomicIsize, AtomicPtr, AtomicUsize};
+}
+
+#[cfg(loom)]
+pub(crate) mod atomic {
+    #[cfg(feature = "hazard")]
+    pub(crate) use loom::sync::atomic::AtomicBool;
+    pub(crate) use loom::sync::atomic::{fence, AtomicIsize, AtomicPtr, AtomicUsize};
+}
+
+#[cfg(loom)]
+pub(crate) use loom::cell::UnsafeCell;
+
+// loom has to be told to run another thread, or it would spin forever
+#[cfg(not(loom))]
+pub(crate) use core::hint::spin_loop;
+#[cfg(loom)]
+pub(crate) use loom::thread::yield_now as spin_loop;
+
+#[cfg(all(feature = "std", loom))]
+pub(crate) use loom::thread::yield_now;
+#[cfg(all(feature = "std", not(loom)))]
+pub(crate) use std::thread::yield_now;
+
+/// [`core::cell::UnsafeCell`] behind loom's closure-based API, so loom can
+/// check that no access races with another.
+#[cfg(not(loom))]
+pub(crate) struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    pub(crate) fn new(data: T) -> UnsafeCell<T> {
+        UnsafeCell(core::cell::UnsafeCell::new(data))
+    }
+
+    #[cfg(feature = "hazard")]
+    pub(crate) fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+
+    pub(crate) fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    pub(crate) fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
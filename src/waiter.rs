@@ -0,0 +1,632 @@
+#[cfg(feature = "async")]
+use alloc::boxed::Box;
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+#[cfg(feature = "async")]
+use core::cell::UnsafeCell;
+#[cfg(feature = "async")]
+use core::iter;
+#[cfg(feature = "async")]
+use core::ptr::{self, NonNull};
+#[cfg(any(feature = "std", feature = "async"))]
+use core::sync::atomic::AtomicUsize;
+#[cfg(feature = "async")]
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+#[cfg(any(feature = "std", feature = "async"))]
+use core::sync::atomic::{fence, Ordering::Relaxed, Ordering::SeqCst};
+#[cfg(feature = "async")]
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+#[cfg(feature = "async")]
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use std::sync::{Condvar, Mutex};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// Parks consumers waiting for a container to become non-empty.
+///
+/// Producers call [`Waiters::notify`] after every push, which costs a `SeqCst`
+/// fence and a load as long as nobody is waiting. Without `std` only tasks can
+/// wait, and without `async` as well there is nothing to do at all.
+pub(crate) struct Waiters {
+    // threads that are parked, or about to park
+    #[cfg(feature = "std")]
+    sleepers: AtomicUsize,
+    // bumped by every notify that finds sleepers
+    #[cfg(feature = "std")]
+    generation: Mutex<usize>,
+    #[cfg(feature = "std")]
+    condvar: Condvar,
+    // shared with every registration holding one of its slots
+    #[cfg(feature = "async")]
+    wakers: Arc<WakerList>,
+}
+
+// slots per segment of a `WakerList`
+#[cfg(feature = "async")]
+const SEGMENT_LEN: usize = 8;
+
+/// A lock-free list of tasks waiting for a container to become non-empty.
+///
+/// Every waiting future claims a slot of its own the first time it comes up
+/// empty and keeps it until it completes or is dropped, so the list never
+/// holds more slots than futures have waited at once. Slots come in segments
+/// that are linked in as needed and only freed along with the list.
+#[cfg(feature = "async")]
+struct WakerList {
+    // null until a task first waits
+    head: AtomicPtr<Segment>,
+    // at least how many slots are armed, so a notify costs a load while nobody waits
+    armed: AtomicUsize,
+}
+
+#[cfg(feature = "async")]
+struct Segment {
+    slots: [Slot; SEGMENT_LEN],
+    next: AtomicPtr<Segment>,
+}
+
+#[cfg(feature = "async")]
+struct Slot {
+    // held by a single registration at a time
+    claimed: AtomicBool,
+    // set by the registration when its task waits, cleared by the notify that picks it
+    armed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Holds a task's waker so its owner can swap it out while a notify takes it,
+/// the same way as `futures`' `AtomicWaker`.
+#[cfg(feature = "async")]
+struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+#[cfg(feature = "async")]
+const IDLE: usize = 0;
+#[cfg(feature = "async")]
+const REGISTERING: usize = 1;
+#[cfg(feature = "async")]
+const WAKING: usize = 2;
+
+/// A future's claim on a slot to wait in, given up when it is dropped.
+///
+/// Every future that pops through [`Waiters::poll_pop`] keeps one of its own
+/// across polls.
+#[cfg(feature = "async")]
+pub(crate) struct Registration {
+    // the list stays alive for as long as we hold one of its slots
+    slot: Option<(Arc<WakerList>, NonNull<Slot>)>,
+    // whether we armed our slot and haven't yet seen a notify disarm it
+    armed: bool,
+}
+
+// the slot is only ever touched through atomics, and by us through `&mut self`
+#[cfg(feature = "async")]
+unsafe impl Send for Registration {}
+
+#[cfg(feature = "async")]
+unsafe impl Sync for Registration {}
+
+impl Waiters {
+    pub(crate) fn new() -> Waiters {
+        Waiters {
+            #[cfg(feature = "std")]
+            sleepers: AtomicUsize::new(0),
+            #[cfg(feature = "std")]
+            generation: Mutex::new(0),
+            #[cfg(feature = "std")]
+            condvar: Condvar::new(),
+            #[cfg(feature = "async")]
+            wakers: Arc::new(WakerList::new()),
+        }
+    }
+
+    /// Wakes a parked thread or task, call it after making an element available.
+    pub(crate) fn notify(&self) {
+        // pairs with the fences in `wait_until` and `poll_pop`: either the sleeper
+        // sees our element when it checks again, or we see the sleeper here
+        #[cfg(any(feature = "std", feature = "async"))]
+        fence(SeqCst);
+
+        #[cfg(feature = "async")]
+        self.wakers.wake_one();
+
+        #[cfg(feature = "std")]
+        self.wake_sleepers(false);
+    }
+
+    /// Wakes every parked thread and task, call it after making several
+    /// elements available at once, or once there will never be any more.
+    pub(crate) fn notify_all(&self) {
+        #[cfg(any(feature = "std", feature = "async"))]
+        fence(SeqCst);
+
+        #[cfg(feature = "async")]
+        self.wakers.wake_all();
+
+        #[cfg(feature = "std")]
+        self.wake_sleepers(true);
+    }
+
+    #[cfg(feature = "std")]
+    fn wake_sleepers(&self, all: bool) {
+        if self.sleepers.load(Relaxed) == 0 {
+            return;
+        }
+
+        let mut generation = self.generation.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+        drop(generation);
+
+        // a single element only lets one of them through, the rest would just go back to sleep
+        if all {
+            self.condvar.notify_all();
+        } else {
+            self.condvar.notify_one();
+        }
+    }
+
+    /// Calls `try_pop` until it returns `Some`, parking in between.
+    ///
+    /// Gives up and returns `None` once `deadline` passes, or never if there is none.
+    #[cfg(feature = "std")]
+    pub(crate) fn wait_until<T>(
+        &self,
+        deadline: Option<Instant>,
+        mut try_pop: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        loop {
+            if let Some(t) = try_pop() {
+                return Some(t);
+            }
+
+            let mut generation = self.generation.lock().unwrap();
+            let seen = *generation;
+
+            self.sleepers.fetch_add(1, SeqCst);
+            fence(SeqCst);
+
+            // a push may have landed right before we registered
+            if let Some(t) = try_pop() {
+                self.sleepers.fetch_sub(1, Relaxed);
+                return Some(t);
+            }
+
+            while *generation == seen {
+                generation = match deadline {
+                    None => self.condvar.wait(generation).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            self.sleepers.fetch_sub(1, Relaxed);
+                            drop(generation);
+                            return try_pop();
+                        }
+
+                        self.condvar
+                            .wait_timeout(generation, deadline - now)
+                            .unwrap()
+                            .0
+                    }
+                };
+            }
+
+            self.sleepers.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// Like [`Waiters::wait_until`], with the deadline `timeout` from now.
+    #[cfg(feature = "std")]
+    pub(crate) fn wait_for<T>(
+        &self,
+        timeout: Duration,
+        try_pop: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        // a timeout too large to represent is as good as none
+        self.wait_until(Instant::now().checked_add(timeout), try_pop)
+    }
+
+    /// Polls `try_pop`, waiting in `registration`'s slot for the next notify if it comes up empty.
+    ///
+    /// `registration` must only ever be used with these waiters.
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_pop<T>(
+        &self,
+        cx: &mut Context<'_>,
+        registration: &mut Registration,
+        mut try_pop: impl FnMut() -> Option<T>,
+    ) -> Poll<T> {
+        registration.observe();
+
+        if let Some(t) = try_pop() {
+            registration.release();
+            return Poll::Ready(t);
+        }
+
+        self.wakers.register(registration, cx.waker());
+        fence(SeqCst);
+
+        // a push may have landed right before we registered
+        match try_pop() {
+            Some(t) => {
+                registration.release();
+                Poll::Ready(t)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl WakerList {
+    fn new() -> WakerList {
+        WakerList {
+            head: AtomicPtr::new(ptr::null_mut()),
+            armed: AtomicUsize::new(0),
+        }
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &Segment> {
+        let mut segment = self.head.load(Acquire);
+        iter::from_fn(move || {
+            let current = unsafe { segment.as_ref()? };
+            segment = current.next.load(Acquire);
+            Some(current)
+        })
+    }
+
+    // claims a free slot, linking in a new segment if every one is taken
+    fn claim(&self) -> NonNull<Slot> {
+        let mut link = &self.head;
+        loop {
+            let mut segment = link.load(Acquire);
+            if segment.is_null() {
+                let new = Box::into_raw(Box::new(Segment::new()));
+                // the first slot is ours before anyone else can see the segment
+                unsafe { (*new).slots[0].claimed.store(true, Relaxed) };
+
+                match link.compare_exchange(ptr::null_mut(), new, AcqRel, Acquire) {
+                    Ok(_) => return NonNull::from(unsafe { &(*new).slots[0] }),
+                    Err(current) => {
+                        drop(unsafe { Box::from_raw(new) });
+                        segment = current;
+                    }
+                }
+            }
+
+            let segment = unsafe { &*segment };
+            for slot in &segment.slots {
+                if !slot.claimed.load(Relaxed)
+                    && slot
+                        .claimed
+                        .compare_exchange(false, true, Acquire, Relaxed)
+                        .is_ok()
+                {
+                    return NonNull::from(slot);
+                }
+            }
+            link = &segment.next;
+        }
+    }
+
+    // hands `waker` to `registration`'s slot, claiming one first if it has none, and arms it
+    fn register(self: &Arc<WakerList>, registration: &mut Registration, waker: &Waker) {
+        let (list, slot) = registration
+            .slot
+            .get_or_insert_with(|| (self.clone(), self.claim()));
+        debug_assert!(
+            Arc::ptr_eq(list, self),
+            "registration used with other waiters"
+        );
+
+        let slot = unsafe { slot.as_ref() };
+        slot.waker.register(waker);
+
+        // still armed if no notify picked us since we last looked, and one that picks
+        // us now wakes the waker we just handed over
+        if !registration.armed {
+            registration.armed = true;
+            // counted before the slot is armed, so the count never falls short
+            self.armed.fetch_add(1, Relaxed);
+            slot.armed.store(true, Relaxed);
+        }
+    }
+
+    // wakes the task in a single armed slot, if there is one
+    fn wake_one(&self) {
+        if self.armed.load(Relaxed) == 0 {
+            return;
+        }
+
+        for segment in self.segments() {
+            for slot in &segment.slots {
+                if slot.disarm() {
+                    self.armed.fetch_sub(1, Relaxed);
+                    slot.wake();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        if self.armed.load(Relaxed) == 0 {
+            return;
+        }
+
+        for segment in self.segments() {
+            for slot in &segment.slots {
+                if slot.disarm() {
+                    self.armed.fetch_sub(1, Relaxed);
+                    slot.wake();
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn slots(&self) -> usize {
+        self.segments().count() * SEGMENT_LEN
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for WakerList {
+    fn drop(&mut self) {
+        let mut segment = *self.head.get_mut();
+        while !segment.is_null() {
+            let mut owned = unsafe { Box::from_raw(segment) };
+            segment = *owned.next.get_mut();
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Segment {
+    fn new() -> Segment {
+        Segment {
+            slots: [const { Slot::new() }; SEGMENT_LEN],
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            claimed: AtomicBool::new(false),
+            armed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    // true if we took the slot's notification, whoever does has to uncount it
+    fn disarm(&self) -> bool {
+        self.armed.load(Relaxed) && self.armed.swap(false, AcqRel)
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AtomicWaker {
+    const fn new() -> AtomicWaker {
+        AtomicWaker {
+            state: AtomicUsize::new(IDLE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    // only ever called by the slot's owner, never twice at once
+    fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(IDLE, REGISTERING, Acquire, Acquire)
+        {
+            Ok(_) => {
+                let current = unsafe { &mut *self.waker.get() };
+                // a waker that wakes the same task is as good as the new one
+                if !current.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                    *current = Some(waker.clone());
+                }
+
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, IDLE, AcqRel, Acquire)
+                    .is_err()
+                {
+                    // a take came in meanwhile and left the waking to us
+                    let waker = current.take();
+                    self.state.store(IDLE, Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // a take is running and may miss the new waker, so wake the task right away
+            Err(_) => waker.wake_by_ref(),
+        }
+    }
+
+    fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, AcqRel) {
+            IDLE => {
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Release);
+                waker
+            }
+            // the owner is registering and wakes the task itself once it sees us
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Registration {
+    pub(crate) fn new() -> Registration {
+        Registration {
+            slot: None,
+            armed: false,
+        }
+    }
+
+    // a notify that disarmed our slot since we last looked is what woke us, and
+    // the poll we are in acts on it
+    fn observe(&mut self) {
+        if let Some((_, slot)) = &self.slot {
+            if self.armed && !unsafe { slot.as_ref() }.armed.load(Acquire) {
+                self.armed = false;
+            }
+        }
+    }
+
+    // gives up our slot, passing on any notify that picked us and we haven't acted on
+    fn release(&mut self) {
+        let (list, slot) = match self.slot.take() {
+            Some(held) => held,
+            None => return,
+        };
+        let slot = unsafe { slot.as_ref() };
+
+        if self.armed {
+            self.armed = false;
+            if slot.armed.swap(false, AcqRel) {
+                list.armed.fetch_sub(1, Relaxed);
+            } else {
+                fence(SeqCst);
+                list.wake_one();
+            }
+        }
+
+        drop(slot.waker.take());
+        slot.claimed.store(false, Release);
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Runs `future` to completion on the current thread, parking it while the future is pending.
+#[cfg(all(test, feature = "async"))]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn registrations_stay_bounded() {
+    use core::future::{self, Future};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Task;
+
+    impl Wake for Task {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let task = Arc::new(Task);
+    let waker = Waker::from(task.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let waiters = Waiters::new();
+    let waiters = &waiters;
+    let pop = || {
+        let mut registration = Registration::new();
+        Box::pin(future::poll_fn(move |cx| {
+            waiters.poll_pop(cx, &mut registration, || None::<()>)
+        }))
+    };
+
+    for _ in 0..1000 {
+        let mut futures: Vec<_> = (0..4).map(|_| pop()).collect();
+        for _ in 0..10 {
+            for future in &mut futures {
+                assert!(future.as_mut().poll(&mut cx).is_pending());
+            }
+        }
+
+        // cancelled futures give back their slots, and let go of the task
+        drop(futures);
+        assert_eq!(Arc::strong_count(&task), 2);
+    }
+
+    assert_eq!(waiters.wakers.slots(), SEGMENT_LEN);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn notify_wakes_one() {
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Task(AtomicUsize);
+
+    impl Wake for Task {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    let waiters = Waiters::new();
+    let tasks: Vec<_> = (0..3)
+        .map(|_| Arc::new(Task(AtomicUsize::new(0))))
+        .collect();
+    let mut registrations: Vec<_> = (0..3).map(|_| Registration::new()).collect();
+    for (task, registration) in tasks.iter().zip(&mut registrations) {
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(waiters
+            .poll_pop(&mut cx, registration, || None::<()>)
+            .is_pending());
+    }
+    let woken = || {
+        tasks
+            .iter()
+            .map(|task| task.0.load(Relaxed))
+            .collect::<Vec<_>>()
+    };
+
+    waiters.notify();
+    assert_eq!(woken().iter().sum::<usize>(), 1);
+
+    // a woken task that gives up before popping passes the notify on
+    let first = woken().iter().position(|&n| n == 1).unwrap();
+    registrations[first] = Registration::new();
+    assert_eq!(woken().iter().sum::<usize>(), 2);
+
+    waiters.notify_all();
+    assert_eq!(woken(), [1, 1, 1]);
+}
@@ -0,0 +1,8 @@
+use lockfreequeue::mpsc::MpscQueue;
+
+fn main() {
+    let (producer, consumer) = MpscQueue::<u8>::new().split();
+
+    let _second_producer = producer.clone();
+    let _second_consumer = consumer.clone();
+}
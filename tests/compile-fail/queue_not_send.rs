@@ -0,0 +1,9 @@
+use std::rc::Rc;
+
+use lockfreequeue::Queue;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    assert_send::<Queue<Rc<u8>>>();
+}
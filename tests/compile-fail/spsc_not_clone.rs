@@ -0,0 +1,8 @@
+use lockfreequeue::spsc::RingBuffer;
+
+fn main() {
+    let (producer, consumer) = RingBuffer::<u8>::new(8).split();
+
+    let _second_producer = producer.clone();
+    let _second_consumer = consumer.clone();
+}
@@ -0,0 +1,15 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+
+use lockfreequeue::Stack;
+
+fn main() {
+    let stack = Arc::new(Stack::new());
+    stack.push(Rc::new(1u8));
+
+    let our_copy = stack.clone();
+    thread::spawn(move || {
+        our_copy.pop();
+    });
+}
@@ -0,0 +1,9 @@
+use std::rc::Rc;
+
+use lockfreequeue::Stack;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<Stack<Rc<u8>>>();
+}
@@ -0,0 +1,7 @@
+use lockfreequeue::deque::Worker;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<Worker<u8>>();
+}
@@ -0,0 +1,7 @@
+// the cases build their containers through `new`, which only `flize` provides
+#[cfg(feature = "flize")]
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile-fail/*.rs");
+}
@@ -0,0 +1,254 @@
+//! A linearizability checker for recorded concurrent histories.
+//!
+//! Threads log every operation through a shared [`Clock`], which stamps it
+//! when it is called and when it returns. [`check`] then looks for a
+//! sequential order that respects those stamps and matches the [`Spec`],
+//! using Wing and Gong's search with Lowe's memoization of visited states.
+//! Operations on independent objects are split up by [`Spec::partition`] and
+//! checked one object at a time, which linearizability's locality allows.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+
+/// The sequential object a history is checked against.
+pub trait Spec {
+    type State: Clone + Eq + Hash;
+    type Op: Clone + Debug;
+    type Ret: Clone + PartialEq + Debug;
+
+    fn init() -> Self::State;
+
+    /// Applies `op` to `state`, returning whatever the sequential object would.
+    fn apply(state: &mut Self::State, op: &Self::Op) -> Self::Ret;
+
+    /// Which independent object `op` acts on, operations on different ones are checked separately.
+    fn partition(_op: &Self::Op) -> usize {
+        0
+    }
+}
+
+pub struct Operation<S: Spec> {
+    thread: usize,
+    op: S::Op,
+    // `None` while the operation is pending, in which case it may or may not have taken effect
+    ret: Option<S::Ret>,
+    call: u64,
+    returned: u64,
+}
+
+/// Hands out the timestamps every [`Log`] stamps its operations with.
+pub struct Clock(AtomicU64);
+
+/// The operations one thread ran, in order.
+pub struct Log<'a, S: Spec> {
+    clock: &'a Clock,
+    thread: usize,
+    ops: Vec<Operation<S>>,
+}
+
+pub struct History<S: Spec> {
+    ops: Vec<Operation<S>>,
+}
+
+/// The shortest prefix of a history that is not linearizable.
+pub struct Counterexample<S: Spec> {
+    ops: Vec<Operation<S>>,
+}
+
+impl<S: Spec> Clone for Operation<S> {
+    fn clone(&self) -> Operation<S> {
+        Operation {
+            thread: self.thread,
+            op: self.op.clone(),
+            ret: self.ret.clone(),
+            call: self.call,
+            returned: self.returned,
+        }
+    }
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock(AtomicU64::new(0))
+    }
+
+    pub fn log<S: Spec>(&self, thread: usize) -> Log<'_, S> {
+        Log {
+            clock: self,
+            thread,
+            ops: Vec::new(),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.0.fetch_add(1, SeqCst)
+    }
+}
+
+impl<'a, S: Spec> Log<'a, S> {
+    /// Runs `f` as the operation `op`, recording what it returns.
+    pub fn call(&mut self, op: S::Op, f: impl FnOnce() -> S::Ret) -> S::Ret {
+        let call = self.clock.tick();
+        let ret = f();
+        let returned = self.clock.tick();
+
+        self.ops.push(Operation {
+            thread: self.thread,
+            op,
+            ret: Some(ret.clone()),
+            call,
+            returned,
+        });
+        ret
+    }
+}
+
+impl<S: Spec> History<S> {
+    pub fn new<'a>(logs: impl IntoIterator<Item = Log<'a, S>>) -> History<S>
+    where
+        S: 'a,
+    {
+        let mut ops: Vec<_> = logs.into_iter().flat_map(|log| log.ops).collect();
+        ops.sort_by_key(|o| o.call);
+
+        History { ops }
+    }
+}
+
+impl<S: Spec> Counterexample<S> {
+    pub fn operations(&self) -> &[Operation<S>] {
+        &self.ops
+    }
+}
+
+/// Checks `history` against `S`, returning the shortest failing prefix if it is not linearizable.
+pub fn check<S: Spec>(history: &History<S>) -> Result<(), Counterexample<S>> {
+    let mut partitions = BTreeMap::<_, Vec<_>>::new();
+    for o in &history.ops {
+        partitions
+            .entry(S::partition(&o.op))
+            .or_default()
+            .push(o.clone());
+    }
+
+    for ops in partitions.values() {
+        if !linearizable::<S>(ops) {
+            return Err(Counterexample {
+                ops: shortest_failing_prefix::<S>(ops),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[track_caller]
+pub fn assert_linearizable<S: Spec>(history: &History<S>) {
+    if let Err(counterexample) = check(history) {
+        panic!("{:?}", counterexample);
+    }
+}
+
+// linearizable histories are closed under prefixes, so the first failing one
+// can be found by bisecting over the times operations returned
+fn shortest_failing_prefix<S: Spec>(ops: &[Operation<S>]) -> Vec<Operation<S>> {
+    let mut ends: Vec<_> = ops.iter().map(|o| o.returned).collect();
+    ends.sort_unstable();
+
+    // the prefix ending at `ends[hi]` always fails, the one at `ends[lo - 1]` never does
+    let (mut lo, mut hi) = (0, ends.len() - 1);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if linearizable::<S>(&prefix(ops, ends[mid])) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    prefix(ops, ends[hi])
+}
+
+// everything called by `end`, with operations still running then turned pending
+fn prefix<S: Spec>(ops: &[Operation<S>], end: u64) -> Vec<Operation<S>> {
+    ops.iter()
+        .filter(|o| o.call < end)
+        .map(|o| {
+            let mut o = o.clone();
+            if o.returned > end {
+                o.ret = None;
+                o.returned = u64::MAX;
+            }
+            o
+        })
+        .collect()
+}
+
+fn linearizable<S: Spec>(ops: &[Operation<S>]) -> bool {
+    let mut done = vec![false; ops.len()];
+    search::<S>(ops, &mut done, S::init(), &mut HashSet::new())
+}
+
+// tries every operation that could take effect next, depth first
+fn search<S: Spec>(
+    ops: &[Operation<S>],
+    done: &mut Vec<bool>,
+    state: S::State,
+    seen: &mut HashSet<(Vec<bool>, S::State)>,
+) -> bool {
+    let remaining = || {
+        ops.iter()
+            .zip(done.iter())
+            .filter(|(_, &d)| !d)
+            .map(|(o, _)| o)
+    };
+
+    // pending operations never have to take effect
+    if remaining().all(|o| o.ret.is_none()) {
+        return true;
+    }
+
+    // an operation can only go next if nothing left returned before it was called
+    let horizon = remaining().map(|o| o.returned).min().unwrap();
+    let candidates: Vec<_> = (0..ops.len())
+        .filter(|&i| !done[i] && ops[i].call < horizon)
+        .collect();
+
+    for i in candidates {
+        let mut next = state.clone();
+        let ret = S::apply(&mut next, &ops[i].op);
+        if ops[i].ret.as_ref().is_some_and(|r| *r != ret) {
+            continue;
+        }
+
+        done[i] = true;
+        if seen.insert((done.clone(), next.clone())) && search::<S>(ops, done, next, seen) {
+            return true;
+        }
+        done[i] = false;
+    }
+
+    false
+}
+
+impl<S: Spec> Debug for Counterexample<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "history is not linearizable, shortest failing prefix:")?;
+        for o in &self.ops {
+            match &o.ret {
+                Some(ret) => writeln!(
+                    f,
+                    "  [{:>6}, {:>6}] thread {}: {:?} -> {:?}",
+                    o.call, o.returned, o.thread, o.op, ret
+                )?,
+                None => writeln!(
+                    f,
+                    "  [{:>6},    ...] thread {}: {:?} pending",
+                    o.call, o.thread, o.op
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
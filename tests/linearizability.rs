@@ -0,0 +1,392 @@
+//! Records histories of every multi-consumer container under contention and
+//! checks them for linearizability against a sequential model.
+
+mod lincheck;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+
+use lockfreequeue::deque::{Steal, Worker};
+use lockfreequeue::{ArrayQueue, EliminationStack, Queue, Stack};
+
+use crate::lincheck::{assert_linearizable, check, Clock, History, Log, Spec};
+
+const THREADS: usize = 4;
+const OPS: usize = 200;
+const ROUNDS: usize = 10;
+
+// only `flize` gives the containers a default, the others have to be named
+#[cfg(feature = "flize")]
+type Backend = lockfreequeue::reclaim::Flize;
+#[cfg(all(not(feature = "flize"), feature = "crossbeam-epoch"))]
+type Backend = lockfreequeue::reclaim::CrossbeamEpoch;
+#[cfg(all(
+    not(feature = "flize"),
+    not(feature = "crossbeam-epoch"),
+    feature = "hazard"
+))]
+type Backend = lockfreequeue::reclaim::HazardPointers;
+#[cfg(all(
+    not(feature = "flize"),
+    not(feature = "crossbeam-epoch"),
+    not(feature = "hazard"),
+    feature = "leak"
+))]
+type Backend = lockfreequeue::reclaim::Leak;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Push(u32),
+    Pop,
+}
+
+struct StackSpec;
+
+struct QueueSpec;
+
+/// A queue that hands back pushes beyond `CAP` elements.
+struct BoundedQueueSpec<const CAP: usize>;
+
+/// Independent stacks, told apart by their index.
+struct Keyed<S>(S);
+
+/// The deque as seen from its worker, which pops the newest element, and its stealers, which take the oldest.
+struct DequeSpec;
+
+#[derive(Clone, Debug)]
+enum DequeOp {
+    Push(u32),
+    Pop,
+    Steal,
+}
+
+// pushes return nothing, unless a bounded queue hands the value back
+impl Spec for StackSpec {
+    type State = Vec<u32>;
+    type Op = Op;
+    type Ret = Option<u32>;
+
+    fn init() -> Vec<u32> {
+        Vec::new()
+    }
+
+    fn apply(state: &mut Vec<u32>, op: &Op) -> Option<u32> {
+        match *op {
+            Op::Push(t) => {
+                state.push(t);
+                None
+            }
+            Op::Pop => state.pop(),
+        }
+    }
+}
+
+impl Spec for QueueSpec {
+    type State = VecDeque<u32>;
+    type Op = Op;
+    type Ret = Option<u32>;
+
+    fn init() -> VecDeque<u32> {
+        VecDeque::new()
+    }
+
+    fn apply(state: &mut VecDeque<u32>, op: &Op) -> Option<u32> {
+        match *op {
+            Op::Push(t) => {
+                state.push_back(t);
+                None
+            }
+            Op::Pop => state.pop_front(),
+        }
+    }
+}
+
+impl<const CAP: usize> Spec for BoundedQueueSpec<CAP> {
+    type State = VecDeque<u32>;
+    type Op = Op;
+    type Ret = Option<u32>;
+
+    fn init() -> VecDeque<u32> {
+        VecDeque::new()
+    }
+
+    fn apply(state: &mut VecDeque<u32>, op: &Op) -> Option<u32> {
+        match *op {
+            Op::Push(t) if state.len() == CAP => Some(t),
+            _ => QueueSpec::apply(state, op),
+        }
+    }
+}
+
+impl<S: Spec> Spec for Keyed<S> {
+    type State = S::State;
+    type Op = (usize, S::Op);
+    type Ret = S::Ret;
+
+    fn init() -> S::State {
+        S::init()
+    }
+
+    fn apply(state: &mut S::State, (_, op): &(usize, S::Op)) -> S::Ret {
+        S::apply(state, op)
+    }
+
+    fn partition(&(key, _): &(usize, S::Op)) -> usize {
+        key
+    }
+}
+
+impl Spec for DequeSpec {
+    type State = VecDeque<u32>;
+    type Op = DequeOp;
+    type Ret = Option<u32>;
+
+    fn init() -> VecDeque<u32> {
+        VecDeque::new()
+    }
+
+    fn apply(state: &mut VecDeque<u32>, op: &DequeOp) -> Option<u32> {
+        match *op {
+            DequeOp::Push(t) => {
+                state.push_back(t);
+                None
+            }
+            DequeOp::Pop => state.pop_back(),
+            DequeOp::Steal => state.pop_front(),
+        }
+    }
+}
+
+// a xorshift generator per thread, so runs differ between threads but not between rounds
+struct Rng(u32);
+
+impl Rng {
+    fn new(thread: usize) -> Rng {
+        Rng(0x9e37_79b9 ^ (thread as u32 + 1).wrapping_mul(0x85eb_ca6b))
+    }
+
+    fn coin(&mut self) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 & 1 == 0
+    }
+}
+
+// every pushed value is unique, which keeps the search from branching on look-alikes
+fn value(thread: usize, i: usize) -> u32 {
+    (thread * OPS + i) as u32
+}
+
+// runs `body` on `THREADS` threads at once, each with its own log
+fn record<S: Spec>(body: impl Fn(usize, &mut Log<'_, S>) + Sync) -> History<S>
+where
+    S::Op: Send,
+    S::Ret: Send,
+{
+    let clock = Clock::new();
+    let logs: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let (clock, body) = (&clock, &body);
+                s.spawn(move || {
+                    let mut log = clock.log(thread);
+                    body(thread, &mut log);
+                    log
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    History::new(logs)
+}
+
+#[test]
+fn stack() {
+    for _ in 0..ROUNDS {
+        let stack = Stack::with_reclaimer(Backend::default());
+        let history = record::<StackSpec>(|thread, log| {
+            let mut rng = Rng::new(thread);
+            for i in 0..OPS {
+                if rng.coin() {
+                    let t = value(thread, i);
+                    log.call(Op::Push(t), || {
+                        stack.push(t);
+                        None
+                    });
+                } else {
+                    log.call(Op::Pop, || stack.pop());
+                }
+            }
+        });
+        assert_linearizable(&history);
+    }
+}
+
+#[test]
+fn elimination_stack() {
+    for _ in 0..ROUNDS {
+        // a single slot, so colliding pushes and pops pair up often
+        let stack = EliminationStack::from_stack(Stack::with_reclaimer(Backend::default()), 1);
+        let history = record::<StackSpec>(|thread, log| {
+            let mut rng = Rng::new(thread);
+            for i in 0..OPS {
+                if rng.coin() {
+                    let t = value(thread, i);
+                    log.call(Op::Push(t), || {
+                        stack.push(t);
+                        None
+                    });
+                } else {
+                    log.call(Op::Pop, || stack.pop());
+                }
+            }
+        });
+        assert_linearizable(&history);
+    }
+}
+
+#[test]
+fn stacks_checked_apart() {
+    for _ in 0..ROUNDS {
+        let stacks = [
+            Stack::with_reclaimer(Backend::default()),
+            Stack::with_reclaimer(Backend::default()),
+        ];
+        let history = record::<Keyed<StackSpec>>(|thread, log| {
+            let mut rng = Rng::new(thread);
+            for i in 0..OPS {
+                let key = i % stacks.len();
+                if rng.coin() {
+                    let t = value(thread, i);
+                    log.call((key, Op::Push(t)), || {
+                        stacks[key].push(t);
+                        None
+                    });
+                } else {
+                    log.call((key, Op::Pop), || stacks[key].pop());
+                }
+            }
+        });
+        assert_linearizable(&history);
+    }
+}
+
+#[test]
+fn queue() {
+    for _ in 0..ROUNDS {
+        let queue = Queue::with_reclaimer(Backend::default());
+        let history = record::<QueueSpec>(|thread, log| {
+            let mut rng = Rng::new(thread);
+            for i in 0..OPS {
+                if rng.coin() {
+                    let t = value(thread, i);
+                    log.call(Op::Push(t), || {
+                        queue.push(t);
+                        None
+                    });
+                } else {
+                    log.call(Op::Pop, || queue.pop());
+                }
+            }
+        });
+        assert_linearizable(&history);
+    }
+}
+
+#[test]
+fn array_queue() {
+    for _ in 0..ROUNDS {
+        // small enough that pushes regularly find it full
+        let queue = ArrayQueue::new(4);
+        let history = record::<BoundedQueueSpec<4>>(|thread, log| {
+            let mut rng = Rng::new(thread);
+            for i in 0..OPS {
+                if rng.coin() {
+                    let t = value(thread, i);
+                    log.call(Op::Push(t), || queue.try_push(t).err());
+                } else {
+                    log.call(Op::Pop, || queue.pop());
+                }
+            }
+        });
+        assert_linearizable(&history);
+    }
+}
+
+#[test]
+fn deque() {
+    for _ in 0..ROUNDS {
+        let worker = Worker::with_reclaimer(Backend::default());
+        let stealer = worker.stealer();
+        // the worker can't be shared, so thread 0 borrows it through a lock nobody else takes
+        let worker = Mutex::new(worker);
+        let history = record::<DequeSpec>(|thread, log| {
+            let mut rng = Rng::new(thread);
+            for i in 0..OPS {
+                if thread != 0 {
+                    // a retry leaves the deque as it was, as if it never ran
+                    log.call(DequeOp::Steal, || loop {
+                        match stealer.steal() {
+                            Steal::Retry => continue,
+                            Steal::Empty => break None,
+                            Steal::Success(t) => break Some(t),
+                        }
+                    });
+                    continue;
+                }
+
+                let worker = worker.lock().unwrap();
+                if rng.coin() {
+                    let t = value(thread, i);
+                    log.call(DequeOp::Push(t), || {
+                        worker.push(t);
+                        None
+                    });
+                } else {
+                    log.call(DequeOp::Pop, || worker.pop());
+                }
+            }
+        });
+        assert_linearizable(&history);
+    }
+}
+
+#[test]
+fn catches_lost_push() {
+    let clock = Clock::new();
+    let mut first = clock.log::<StackSpec>(0);
+    let mut second = clock.log::<StackSpec>(1);
+
+    second.call(Op::Push(2), || None);
+    second.call(Op::Pop, || Some(2));
+    // the push returned before the pop was called, so the pop can't miss it
+    first.call(Op::Push(1), || None);
+    second.call(Op::Pop, || None);
+    first.call(Op::Push(3), || None);
+
+    let counterexample = check(&History::new([first, second])).unwrap_err();
+    // everything up to the empty pop, but not the push after it
+    assert_eq!(counterexample.operations().len(), 4);
+}
+
+#[test]
+fn accepts_overlapping_calls() {
+    let clock = Clock::new();
+    let mut first = clock.log::<QueueSpec>(0);
+    let mut second = clock.log::<QueueSpec>(1);
+
+    // the pop runs while the push is in flight, so either may take effect first
+    first.call(Op::Push(1), || {
+        second.call(Op::Pop, || Some(1));
+        None
+    });
+    first.call(Op::Push(2), || {
+        second.call(Op::Pop, || None);
+        None
+    });
+
+    assert!(check(&History::new([first, second])).is_ok());
+}
@@ -0,0 +1,438 @@
+//! Model-checks every container under loom, through each interleaving of a
+//! few threads and each value a load may return under the memory model.
+//!
+//! Only built under `cfg(loom)` with the `hazard` feature, using hazard
+//! pointers as the reclaimer since they are the only backend whose atomics
+//! loom can see:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --test loom --no-default-features --features std,hazard
+//! ```
+//!
+//! The search is bounded to three preemptions per run, set `LOOM_MAX_PREEMPTIONS`
+//! to change that.
+
+#![cfg(all(loom, feature = "hazard"))]
+
+use std::ptr::NonNull;
+
+use loom::sync::Arc;
+use loom::thread;
+
+use lockfreequeue::channel::{self, TryRecvError};
+use lockfreequeue::deque::{Steal, Worker};
+use lockfreequeue::mpsc::intrusive::{self, Link, Linked};
+use lockfreequeue::mpsc::MpscQueue;
+use lockfreequeue::reclaim::HazardPointers;
+use lockfreequeue::spsc::RingBuffer;
+use lockfreequeue::{ArrayQueue, EliminationStack, Queue, Stack};
+
+// an unbounded search over three threads takes far too long to run on every change
+fn model(f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = loom::model::Builder::new();
+    if builder.preemption_bound.is_none() {
+        builder.preemption_bound = Some(3);
+    }
+    builder.check(f);
+}
+
+// scanning on every retire makes loom explore frees racing with reads
+fn reclaimer() -> HazardPointers {
+    HazardPointers::with_scan_threshold(1)
+}
+
+fn sorted(mut v: Vec<i32>) -> Vec<i32> {
+    v.sort_unstable();
+    v
+}
+
+#[test]
+fn stack_push_pop() {
+    model(|| {
+        let stack = Arc::new(Stack::with_reclaimer(reclaimer()));
+        stack.push(1);
+
+        let pusher = {
+            let stack = stack.clone();
+            thread::spawn(move || stack.push(2))
+        };
+        let popper = {
+            let stack = stack.clone();
+            thread::spawn(move || stack.pop())
+        };
+
+        pusher.join().unwrap();
+        let mut popped: Vec<_> = popper.join().unwrap().into_iter().collect();
+        while let Some(t) = stack.pop() {
+            popped.push(t);
+        }
+        assert_eq!(sorted(popped), [1, 2]);
+    });
+}
+
+#[test]
+fn stack_concurrent_pops() {
+    model(|| {
+        let stack = Arc::new(Stack::with_reclaimer(reclaimer()));
+        stack.push(1);
+        stack.push(2);
+
+        let poppers: Vec<_> = (0..2)
+            .map(|_| {
+                let stack = stack.clone();
+                thread::spawn(move || stack.pop())
+            })
+            .collect();
+
+        let popped = poppers
+            .into_iter()
+            .map(|popper| popper.join().unwrap().unwrap())
+            .collect();
+        assert_eq!(sorted(popped), [1, 2]);
+        assert!(stack.pop().is_none());
+    });
+}
+
+#[test]
+fn stack_push_all_take_all() {
+    model(|| {
+        let stack = Arc::new(Stack::with_reclaimer(reclaimer()));
+        stack.push(1);
+
+        let pusher = {
+            let stack = stack.clone();
+            thread::spawn(move || stack.push_all([2, 3]))
+        };
+        let taker = {
+            let stack = stack.clone();
+            thread::spawn(move || stack.take_all().collect::<Vec<_>>())
+        };
+
+        pusher.join().unwrap();
+        let mut taken = taker.join().unwrap();
+        taken.extend(stack.take_all());
+        assert_eq!(sorted(taken), [1, 2, 3]);
+    });
+}
+
+// a single slot, so every collision on `head` meets in the same place
+fn elimination_stack() -> EliminationStack<i32, HazardPointers> {
+    EliminationStack::from_stack(Stack::with_reclaimer(reclaimer()), 1)
+}
+
+#[test]
+fn elimination_stack_push_pop() {
+    model(|| {
+        let stack = Arc::new(elimination_stack());
+        stack.push(1);
+
+        let pusher = {
+            let stack = stack.clone();
+            thread::spawn(move || stack.push(2))
+        };
+        let popper = {
+            let stack = stack.clone();
+            thread::spawn(move || stack.pop())
+        };
+
+        pusher.join().unwrap();
+        let mut popped: Vec<_> = popper.join().unwrap().into_iter().collect();
+        while let Some(t) = stack.pop() {
+            popped.push(t);
+        }
+        assert_eq!(sorted(popped), [1, 2]);
+    });
+}
+
+#[test]
+fn elimination_stack_pairs_up() {
+    model(|| {
+        let stack = Arc::new(elimination_stack());
+        stack.push(1);
+        stack.push(2);
+
+        // the first pop moves `head` under the push and the second pop, which then meet in the slot
+        let poppers: Vec<_> = (0..2)
+            .map(|_| {
+                let stack = stack.clone();
+                thread::spawn(move || stack.pop())
+            })
+            .collect();
+        stack.push(3);
+
+        let mut popped: Vec<_> = poppers
+            .into_iter()
+            .map(|popper| popper.join().unwrap().unwrap())
+            .collect();
+        popped.extend(stack.pop());
+        assert_eq!(sorted(popped), [1, 2, 3]);
+        assert!(stack.pop().is_none());
+    });
+}
+
+#[test]
+fn queue_push_pop() {
+    model(|| {
+        let queue = Arc::new(Queue::with_reclaimer(reclaimer()));
+        queue.push(1);
+
+        let pusher = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push(2))
+        };
+        let popper = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.pop())
+        };
+
+        pusher.join().unwrap();
+        // the element pushed before any thread started is always the oldest
+        assert_eq!(popper.join().unwrap(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert!(queue.pop().is_none());
+    });
+}
+
+#[test]
+fn queue_concurrent_pushes() {
+    model(|| {
+        let queue = Arc::new(Queue::with_reclaimer(reclaimer()));
+
+        let pushers: Vec<_> = (1..3)
+            .map(|i| {
+                let queue = queue.clone();
+                thread::spawn(move || queue.push(i))
+            })
+            .collect();
+        let popped = queue.pop();
+
+        for pusher in pushers {
+            pusher.join().unwrap();
+        }
+        let mut popped: Vec<_> = popped.into_iter().collect();
+        while let Some(t) = queue.pop() {
+            popped.push(t);
+        }
+        assert_eq!(sorted(popped), [1, 2]);
+    });
+}
+
+#[test]
+fn array_queue_push_pop() {
+    model(|| {
+        let queue = Arc::new(ArrayQueue::new(2));
+
+        let pusher = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                queue.try_push(1).unwrap();
+                queue.try_push(2).unwrap();
+            })
+        };
+        let popped = queue.pop();
+
+        pusher.join().unwrap();
+        let mut popped: Vec<_> = popped.into_iter().collect();
+        while let Some(t) = queue.pop() {
+            popped.push(t);
+        }
+        assert_eq!(popped, [1, 2]);
+    });
+}
+
+#[test]
+fn array_queue_wraps_around() {
+    model(|| {
+        let queue = Arc::new(ArrayQueue::new(1));
+        queue.try_push(1).unwrap();
+
+        // a push into the slot a concurrent pop is emptying either fails or lands behind it
+        let pusher = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.try_push(2).is_ok())
+        };
+        let popped = queue.pop();
+
+        let pushed = pusher.join().unwrap();
+        assert_eq!(popped, Some(1));
+        assert_eq!(queue.pop(), pushed.then_some(2));
+    });
+}
+
+#[test]
+fn array_queue_sees_finished_operations() {
+    model(|| {
+        let queue = Arc::new(ArrayQueue::new(2));
+
+        let pushers: Vec<_> = (1..3)
+            .map(|i| {
+                let queue = queue.clone();
+                thread::spawn(move || queue.try_push(i).unwrap())
+            })
+            .collect();
+
+        // once a push has returned the queue is not empty, even while an earlier one is still writing
+        pushers.into_iter().next_back().unwrap().join().unwrap();
+        assert!(queue.pop().is_some());
+    });
+}
+
+#[test]
+fn mpsc_push_pop() {
+    model(|| {
+        let (producer, mut consumer) = MpscQueue::new().split();
+
+        let producers: Vec<_> = (1..3)
+            .map(|i| {
+                let producer = producer.clone();
+                thread::spawn(move || producer.push(i))
+            })
+            .collect();
+        let popped = consumer.pop();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut popped: Vec<_> = popped.into_iter().collect();
+        while let Some(t) = consumer.pop() {
+            popped.push(t);
+        }
+        assert_eq!(sorted(popped), [1, 2]);
+    });
+}
+
+#[repr(C)]
+struct Message {
+    link: Link,
+    value: i32,
+}
+
+unsafe impl Linked for Message {
+    fn link(this: NonNull<Message>) -> NonNull<Link> {
+        this.cast()
+    }
+
+    unsafe fn from_link(link: NonNull<Link>) -> NonNull<Message> {
+        link.cast()
+    }
+}
+
+// sends the pointer over to the producer thread, the queue only ever hands it back
+struct Sent(NonNull<Message>);
+
+unsafe impl Send for Sent {}
+
+#[test]
+fn intrusive_mpsc_push_pop() {
+    model(|| {
+        let (producer, mut consumer) = intrusive::MpscQueue::new().split();
+
+        let producers: Vec<_> = (1..3)
+            .map(|value| {
+                let producer = producer.clone();
+                let message = Sent(NonNull::from(Box::leak(Box::new(Message {
+                    link: Link::new(),
+                    value,
+                }))));
+                thread::spawn(move || {
+                    let message = message;
+                    unsafe { producer.push(message.0) }
+                })
+            })
+            .collect();
+        // popping the only element in line puts the stub back, racing the other push
+        let popped = consumer.pop();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut popped: Vec<_> = popped.into_iter().collect();
+        while let Some(m) = consumer.pop() {
+            popped.push(m);
+        }
+        let values = popped
+            .into_iter()
+            .map(|m| unsafe { Box::from_raw(m.as_ptr()).value })
+            .collect();
+        assert_eq!(sorted(values), [1, 2]);
+    });
+}
+
+#[test]
+fn spsc_push_pop() {
+    model(|| {
+        let (mut producer, mut consumer) = RingBuffer::new(1).split();
+
+        let pusher = thread::spawn(move || {
+            producer.try_push(1).unwrap();
+            producer.try_push(2).is_ok()
+        });
+        let popped = consumer.pop();
+
+        let pushed = pusher.join().unwrap();
+        let mut popped: Vec<_> = popped.into_iter().collect();
+        while let Some(t) = consumer.pop() {
+            popped.push(t);
+        }
+        assert_eq!(popped, if pushed { vec![1, 2] } else { vec![1] });
+    });
+}
+
+#[test]
+fn deque_pop_steal() {
+    model(|| {
+        let worker = Worker::with_reclaimer(reclaimer());
+        worker.push(1);
+        worker.push(2);
+
+        let stealer = worker.stealer();
+        let thief = thread::spawn(move || match stealer.steal() {
+            Steal::Success(t) => Some(t),
+            Steal::Empty | Steal::Retry => None,
+        });
+        let popped = worker.pop();
+
+        let mut taken: Vec<_> = thief.join().unwrap().into_iter().chain(popped).collect();
+        while let Some(t) = worker.pop() {
+            taken.push(t);
+        }
+        assert_eq!(sorted(taken), [1, 2]);
+    });
+}
+
+#[test]
+fn deque_last_element() {
+    model(|| {
+        let worker = Worker::with_reclaimer(reclaimer());
+        worker.push(1);
+
+        // the worker and a stealer race for the only element, exactly one may win
+        let stealer = worker.stealer();
+        let thief = thread::spawn(move || stealer.steal() == Steal::Success(1));
+        let popped = worker.pop();
+
+        let stolen = thief.join().unwrap();
+        assert!(stolen != popped.is_some());
+        assert!(worker.pop().is_none());
+    });
+}
+
+#[test]
+fn channel_disconnect() {
+    model(|| {
+        let (sender, receiver) = channel::channel_with_reclaimer(reclaimer());
+
+        let sender = thread::spawn(move || {
+            sender.send(1).unwrap();
+        });
+        // whatever the last sender sent arrives before the disconnect does
+        let received = receiver.try_recv();
+        assert_ne!(received, Err(TryRecvError::Disconnected));
+
+        sender.join().unwrap();
+        if received.is_err() {
+            assert_eq!(receiver.try_recv(), Ok(1));
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    });
+}